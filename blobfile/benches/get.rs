@@ -0,0 +1,66 @@
+// Lookups through seek + read against the same through the map
+// run with: cargo bench -p blobfile
+
+use blobfile::blobstore::BlobStore;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const N: u64 = 10_000;
+
+fn make_store(fname: &str) -> BlobStore {
+    std::fs::remove_file(fname).ok();
+    let mut bs = BlobStore::new(fname, 1024, N / 8).unwrap();
+    for x in 0..N {
+        bs.insert_only(x, format!("value number {}", x)).unwrap();
+    }
+    bs
+}
+
+fn bench_get(c: &mut Criterion) {
+    let fname = std::env::temp_dir().join("blobfile_bench_get");
+    let fname = fname.to_str().unwrap();
+    drop(make_store(fname));
+
+    let mut group = c.benchmark_group("get");
+    group.sample_size(10);
+
+    let plain = BlobStore::open(fname).unwrap();
+    group.bench_function("seek_read", |b| {
+        b.iter(|| {
+            for x in 0..N {
+                black_box(plain.get(&x).unwrap());
+            }
+        })
+    });
+    drop(plain);
+
+    let mut mapped = BlobStore::open(fname).unwrap().with_mmap().unwrap();
+    group.bench_function("mmap", |b| {
+        b.iter(|| {
+            for x in 0..N {
+                black_box(mapped.get(&x).unwrap());
+            }
+        })
+    });
+    group.bench_function("mmap_ref", |b| {
+        b.iter(|| {
+            for x in 0..N {
+                black_box(mapped.get_ref(&x).unwrap());
+            }
+        })
+    });
+    drop(mapped);
+
+    let mut ro = BlobStore::open_read_only(fname).unwrap();
+    group.bench_function("read_only_ref", |b| {
+        b.iter(|| {
+            for x in 0..N {
+                black_box(ro.get_ref(&x).unwrap());
+            }
+        })
+    });
+    group.finish();
+    std::fs::remove_file(fname).ok();
+}
+
+criterion_group!(benches, bench_get);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use serde::Serialize;
+
+use crate::blob::Blob;
+use crate::error::BlobError;
+
+pub(crate) enum BatchOp {
+    Insert(Blob),
+    // only the key of the blob matters
+    Remove(Blob),
+}
+
+impl BatchOp {
+    pub(crate) fn blob(&self) -> &Blob {
+        match self {
+            BatchOp::Insert(b) | BatchOp::Remove(b) => b,
+        }
+    }
+}
+
+/// Inserts and removes collected up to be put in a BlobStore together
+/// with `write_batch`. They are done a bucket at a time, each bucket read
+/// once and written back a whole block at a time, with one sync at the end.
+/// Within a bucket they happen in the order they were added.
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    /// Puts the pair in, replacing the value if the key is already there
+    pub fn insert<K: Serialize, V: Serialize>(&mut self, k: K, v: V) -> Result<(), BlobError> {
+        self.ops.push(BatchOp::Insert(Blob::from(&k, &v)?));
+        Ok(())
+    }
+
+    pub fn remove<K: Serialize>(&mut self, k: &K) -> Result<(), BlobError> {
+        self.ops.push(BatchOp::Remove(Blob::from(k, &0)?));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -1,3 +1,5 @@
+use std::io::{Read, Seek, Write};
+
 use serde::{Deserialize, Serialize};
 
 use crate::error::BlobError;
@@ -13,9 +15,54 @@ pub fn write_u64<W: std::io::Write>(w: &mut W, data: u64) -> Result<(), BlobErro
     Ok(w.write_all(&ec)?)
 }
 
+/// Bytes a record takes on top of its key and value, the two lengths and the crc
+pub const RECORD_EXTRA: u64 = 20;
+
+/// Set in a record's vlen when its value is kept in the value log,
+/// v is then only where: the entry's place in the log and the value's length
+pub const LOGGED: u64 = 1 << 63;
+const POINTER_LEN: u64 = 16;
+
+/// crc32 of everything before it in a record, lengths included
+pub(crate) fn record_crc(k: &[u8], v: &[u8], logged: bool) -> u32 {
+    let vlen = v.len() as u64 | if logged { LOGGED } else { 0 };
+    let mut h = crc32fast::Hasher::new();
+    h.update(&(k.len() as u64).to_le_bytes());
+    h.update(&vlen.to_le_bytes());
+    h.update(k);
+    h.update(v);
+    h.finalize()
+}
+
+// The hash that picks a key's bucket. Files depend on it not changing,
+// so it is kept here rather than taken from hmap, whose hash may.
+// It gives what hmap::hash(seed, &k) gave when the format was fixed:
+// the length as one word, then the bytes a word at a time. Version 1 is
+// the first format written with it; files from before that used the old
+// hmap::hash and are turned away by the missing magic.
+fn key_hash(seed: u64, k: &[u8]) -> u64 {
+    const K: u64 = 0x9E37_79B9_7F4A_7C15;
+    let add = |state: u64, w: u64| {
+        let x = (state ^ w).wrapping_mul(K);
+        x ^ (x >> 32)
+    };
+    let mut state = add(seed, k.len() as u64);
+    for w in k.chunks(8) {
+        let mut b = [0u8; 8];
+        b[..w.len()].copy_from_slice(w);
+        state = add(state, u64::from_le_bytes(b));
+    }
+    let len = 8 + k.len() as u64;
+    let mut z = state ^ len.wrapping_mul(K);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
 pub struct Blob {
     k: Vec<u8>,
     v: Vec<u8>,
+    logged: bool,
 }
 
 impl Blob {
@@ -23,39 +70,129 @@ impl Blob {
         Ok(Blob {
             k: bincode::serialize(k)?,
             v: bincode::serialize(v)?,
+            logged: false,
         })
     }
 
-    pub fn out<W: std::io::Write>(&self, w: &mut W) -> Result<(), BlobError> {
+    /// For keys and values already encoded some other way than bincode
+    pub fn from_bytes(k: Vec<u8>, v: Vec<u8>) -> Blob {
+        Blob {
+            k,
+            v,
+            logged: false,
+        }
+    }
+
+    /// A record for the bucket saying this blob's value is at pos in the value log
+    pub(crate) fn pointing_to(&self, pos: u64) -> Blob {
+        let mut v = Vec::with_capacity(POINTER_LEN as usize);
+        v.extend_from_slice(&pos.to_le_bytes());
+        v.extend_from_slice(&(self.v.len() as u64).to_le_bytes());
+        Blob {
+            k: self.k.clone(),
+            v,
+            logged: true,
+        }
+    }
+
+    /// Where in the value log the value is and how long, None if it is right here
+    pub(crate) fn log_pointer(&self) -> Option<(u64, u64)> {
+        if !self.logged {
+            return None;
+        }
+        let pos = u64::from_le_bytes(self.v[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(self.v[8..].try_into().unwrap());
+        Some((pos, len))
+    }
+
+    /// The same key with the value read back out of the value log
+    pub(crate) fn with_value(self, v: Vec<u8>) -> Blob {
+        Blob {
+            k: self.k,
+            v,
+            logged: false,
+        }
+    }
+
+    pub fn k_bytes(&self) -> &[u8] {
+        &self.k
+    }
+
+    pub fn v_bytes(&self) -> &[u8] {
+        &self.v
+    }
+
+    fn crc(&self) -> u32 {
+        record_crc(&self.k, &self.v, self.logged)
+    }
+
+    /// Writes klen, vlen, k, v then the crc of all that
+    pub fn out<W: Write>(&self, w: &mut W) -> Result<(), BlobError> {
         let klen = bincode::serialize(&self.k.len())?;
-        let vlen = bincode::serialize(&self.v.len())?;
+        let vlen = self.v.len() as u64 | if self.logged { LOGGED } else { 0 };
+        let vlen = bincode::serialize(&vlen)?;
         w.write_all(&klen)?;
         w.write_all(&vlen)?;
         w.write_all(&self.k)?;
         w.write_all(&self.v)?;
+        w.write_all(&bincode::serialize(&self.crc())?)?;
         Ok(())
     }
 
-    pub fn read<R: std::io::Read>(r: &mut R) -> Result<Blob, failure::Error> {
-        let klen = read_u64(r)? as usize;
-        let vlen = read_u64(r)? as usize;
-        let mut k = vec![0u8; klen];
-        let mut v = vec![0u8; vlen];
+    /// Reads a record back, Corrupt if the crc does not match or
+    /// the lengths make it longer than max, before anything is allocated
+    pub(crate) fn read<R: Read + Seek>(r: &mut R, max: u64) -> Result<Blob, BlobError> {
+        let offset = r.stream_position()?;
+        let klen = read_u64(r)?;
+        let vlen = read_u64(r)?;
+        let logged = vlen & LOGGED != 0;
+        let fits = klen
+            .checked_add(vlen & !LOGGED)
+            .and_then(|n| n.checked_add(RECORD_EXTRA))
+            .is_some_and(|n| n <= max);
+        if !fits {
+            return Err(BlobError::Corrupt {
+                offset,
+                reason: "record is longer than the room it is in".to_string(),
+            });
+        }
+        let mut k = vec![0u8; klen as usize];
+        let mut v = vec![0u8; (vlen & !LOGGED) as usize];
         r.read_exact(&mut k)?;
         r.read_exact(&mut v)?;
-        Ok(Blob { k, v })
+        let mut crc = [0u8; 4];
+        r.read_exact(&mut crc)?;
+        let b = Blob { k, v, logged };
+        if u32::from_le_bytes(crc) != b.crc() {
+            return Err(BlobError::Corrupt {
+                offset,
+                reason: "record checksum does not match".to_string(),
+            });
+        }
+        if logged && b.v.len() as u64 != POINTER_LEN {
+            return Err(BlobError::Corrupt {
+                offset,
+                reason: "value log pointer is the wrong length".to_string(),
+            });
+        }
+        Ok(b)
+    }
+
+    pub fn get_k<'a, K: Deserialize<'a>>(&'a self) -> Result<K, BlobError> {
+        Ok(bincode::deserialize(&self.k)?)
     }
 
     pub fn get_v<'a, V: Deserialize<'a>>(&'a self) -> Result<V, BlobError> {
         Ok(bincode::deserialize(&self.v)?)
     }
 
-    pub fn len(&self) -> u64 {
-        (16 + self.k.len() + self.v.len()) as u64
+    /// Bytes the record takes on disk, never 0
+    pub fn record_len(&self) -> u64 {
+        (RECORD_EXTRA + self.k.len() as u64) + self.v.len() as u64
     }
 
     pub fn k_hash(&self, seed: u64) -> u64 {
-        hmap::hash(seed, &self.k)
+        key_hash(seed, &self.k)
     }
 
     pub fn key_match(&self, rhs: &Self) -> bool {
@@ -66,7 +203,6 @@ impl Blob {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_derive::*;
 
     #[derive(Serialize, Deserialize, PartialEq, Debug)]
     pub struct Point<T> {
@@ -85,17 +221,42 @@ mod tests {
             let mut fout = std::fs::OpenOptions::new()
                 .write(true)
                 .create(true)
+                .truncate(true)
                 .open(tfile)
                 .unwrap();
             blob.out(&mut fout).unwrap();
         }
 
         let mut fin = std::fs::File::open(tfile).unwrap();
-        let b2 = Blob::read(&mut fin).unwrap();
+        assert!(matches!(
+            Blob::read(&mut fin, blob.record_len() - 1),
+            Err(BlobError::Corrupt { offset: 0, .. })
+        ));
+        fin.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let b2 = Blob::read(&mut fin, blob.record_len()).unwrap();
         let v2: String = b2.get_v().unwrap();
         assert_eq!(&v2, v);
 
         let p: Point<i32> = b2.get_v().unwrap();
         assert_eq!(p, Point { x: 11, y: 0 });
+
+        // lengths that would ask for a huge allocation are caught first
+        let mut bad = Vec::new();
+        write_u64(&mut bad, 4).unwrap();
+        write_u64(&mut bad, u64::MAX / 2).unwrap();
+        let mut c = std::io::Cursor::new(bad);
+        assert!(matches!(
+            Blob::read(&mut c, 1000),
+            Err(BlobError::Corrupt { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_key_hash_is_pinned() {
+        // what hmap::hash gave when the format was fixed, this must never change
+        let k = bincode::serialize("a key").unwrap();
+        assert_eq!(key_hash(55, &k), 4092698219455165649);
+        assert_eq!(key_hash(55, &[]), 9901738754777574604);
+        assert_eq!(key_hash(7, &[1u8; 9]), 17297985675047577061);
     }
 }
@@ -0,0 +1,577 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::blob::Blob;
+use crate::blobstore::BlobStore;
+use crate::error::BlobError;
+
+// buckets moved from main to grow on each insert or remove
+const MOVE_STEP: u64 = 2;
+
+fn grow_name(fname: &str) -> String {
+    format!("{}.grow", fname)
+}
+
+// The store is synced first so what is renamed is all on disk, then the
+// directory, so after a crash the new name is there too and not the old one
+fn rename_synced(store: &mut BlobStore, from: &str, to: &str) -> Result<(), BlobError> {
+    store.sync()?;
+    std::fs::rename(from, to)?;
+    let dir = match Path::new(to).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// sizes for a store that will take everything in `from`,
+// need is the length of a blob that did not fit in a block (0 if none)
+fn bigger(from: &BlobStore, need: u64) -> (u64, u64) {
+    let mut bsize = from.block_size();
+    if need > 0 {
+        while bsize < need {
+            bsize *= 2;
+        }
+        return (bsize, from.nblocks());
+    }
+    (bsize, from.nblocks() * 2)
+}
+
+// a new store to move from's records into, sharing its value log if it has one
+fn fresh(fname: &str, bsize: u64, nblocks: u64, from: &BlobStore) -> Result<BlobStore, BlobError> {
+    // anything still here is left over from a crash and can go
+    std::fs::remove_file(fname).ok();
+    let mut res = BlobStore::new(fname, bsize, nblocks)?;
+    res.share_log(from)?;
+    Ok(res)
+}
+
+fn dead(bs: &BlobStore) -> u64 {
+    bs.value_log_stats().map_or(0, |(_, dead)| dead)
+}
+
+// copies every blob in `from` into a new file at fname,
+// making it bigger again until they all fit
+fn rebuild(from: &mut BlobStore, fname: &str, need: u64) -> Result<BlobStore, BlobError> {
+    let (mut bsize, mut nblocks) = bigger(from, need);
+    'retry: loop {
+        let mut res = fresh(fname, bsize, nblocks, from)?;
+        for bucket in 0..from.nblocks() {
+            // values in the log stay there, only the pointers are copied
+            for b in from.raw_blobs(bucket)? {
+                match res.insert_blob(&b) {
+                    Ok(()) => {}
+                    Err(BlobError::NoRoom) => {
+                        nblocks *= 2;
+                        continue 'retry;
+                    }
+                    Err(BlobError::TooBig(n)) => {
+                        (bsize, nblocks) = bigger(&res, n);
+                        continue 'retry;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        res.add_dead(dead(from))?;
+        return Ok(res);
+    }
+}
+
+/// The growing wrapper for BlobStore, works like HMap does:
+/// when a blob will not fit in main a bigger grow file is made,
+/// and every insert or remove after that moves a few of main's buckets over.
+/// Once they are all moved the grow file is renamed over the main one.
+///
+/// The grow file lives next to main as "<fname>.grow", if one is found
+/// when opening, the move was cut short and carries on from bucket 0
+/// (buckets that were already moved are empty in main). Until it gets
+/// to a key left in both, len and iter count that key twice.
+///
+/// If main has a value log, grow appends to the same one and records
+/// pointing into it move as they are, so values are never copied.
+/// The log is only gc'd once nothing is moving.
+pub struct BlobMap {
+    fname: String,
+    main: BlobStore,
+    grow: Option<BlobStore>,
+    n_moved: u64,
+}
+
+impl BlobMap {
+    pub fn new(fname: &str, block_size: u64, nblocks: u64) -> Result<Self, BlobError> {
+        let main = BlobStore::new_or_open(fname, block_size, nblocks)?;
+        Self::with_main(fname, main)
+    }
+
+    pub fn open(fname: &str) -> Result<Self, BlobError> {
+        let main = BlobStore::open(fname)?;
+        Self::with_main(fname, main)
+    }
+
+    // keys and values are bincode here, as in the plain store
+    fn with_main(fname: &str, main: BlobStore) -> Result<Self, BlobError> {
+        main.check_bincode()?;
+        let gname = grow_name(fname);
+        let grow = match Path::new(&gname).exists() {
+            true => Some(BlobStore::open_sharing_log(&gname, fname)?),
+            false => None,
+        };
+        Ok(BlobMap {
+            fname: fname.to_string(),
+            main,
+            grow,
+            n_moved: 0,
+        })
+    }
+
+    /// Values longer than threshold bytes go in the value log from now on,
+    /// anything still moving is moved first
+    pub fn with_value_log(mut self, threshold: u64) -> Result<Self, BlobError> {
+        self.finish_move()?;
+        self.main = self.main.with_value_log(threshold)?;
+        Ok(self)
+    }
+
+    /// The value log's length and how much of it is dead, None without one
+    pub fn value_log_stats(&self) -> Option<(u64, u64)> {
+        let (len, main_dead) = self.main.value_log_stats()?;
+        match self.grow.as_ref().and_then(|g| g.value_log_stats()) {
+            // both append to the log, the longer is the one that saw the end last
+            Some((glen, grow_dead)) => Some((len.max(glen), main_dead + grow_dead)),
+            None => Some((len, main_dead)),
+        }
+    }
+
+    /// True once half the value log is dead
+    pub fn gc_due(&self) -> bool {
+        self.value_log_stats()
+            .is_some_and(|(len, dead)| dead > 0 && dead * 2 >= len)
+    }
+
+    /// Takes back the dead space in the value log, anything still moving is moved first
+    pub fn gc_value_log(&mut self) -> Result<(), BlobError> {
+        self.finish_move()?;
+        self.main.gc_value_log()
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.grow.is_some()
+    }
+
+    /// Pairs in main and grow together. After a crash part way through moving
+    /// a bucket, or between putting a key in grow and taking it out of main,
+    /// that key is in both and counted twice until its bucket is moved again.
+    pub fn len(&self) -> u64 {
+        self.main.len() + self.grow.as_ref().map_or(0, |g| g.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Block size of the store that new blobs go into
+    pub fn block_size(&self) -> u64 {
+        self.grow.as_ref().unwrap_or(&self.main).block_size()
+    }
+
+    /// Number of blocks of the store that new blobs go into
+    pub fn nblocks(&self) -> u64 {
+        self.grow.as_ref().unwrap_or(&self.main).nblocks()
+    }
+
+    /// Puts the pair in, replacing the value if the key is already there.
+    /// Returns the blob that was replaced.
+    pub fn insert<K: Serialize, V: Serialize>(
+        &mut self,
+        k: K,
+        v: V,
+    ) -> Result<Option<Blob>, BlobError> {
+        let blob = self.main.blob_from(&k, &v)?;
+        self.move_next()?;
+        if self.grow.is_none() {
+            match self.main.replace_blob(&blob) {
+                Err(BlobError::NoRoom) => self.start_move(0)?,
+                Err(BlobError::TooBig(n)) => self.start_move(n)?,
+                r => return r,
+            }
+        }
+        // while moving the key may still be in main, take it out once the
+        // new one is in grow, a crash in between leaves both and get finds grow's
+        let old_grow = self.grow_insert(&blob)?;
+        let old = self.main.remove_blob(&blob)?;
+        Ok(old_grow.or(old))
+    }
+
+    /// Only puts the pair in if the key is not there yet, true if it was put in
+    pub fn insert_if_absent<K: Serialize, V: Serialize>(
+        &mut self,
+        k: K,
+        v: V,
+    ) -> Result<bool, BlobError> {
+        match self.get(&k) {
+            Ok(_) => Ok(false),
+            Err(BlobError::NotFound) => self.insert(k, v).map(|_| true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Gets the blob for k, if it is not there the value from f is put in first
+    pub fn get_or_insert_with<K, V, F>(&mut self, k: K, f: F) -> Result<Blob, BlobError>
+    where
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce() -> V,
+    {
+        match self.get(&k) {
+            Err(BlobError::NotFound) => {}
+            r => return r,
+        }
+        let v = f();
+        self.insert(&k, &v)?;
+        self.get(&k)
+    }
+
+    pub fn get<K: Serialize>(&self, k: &K) -> Result<Blob, BlobError> {
+        if let Some(g) = &self.grow {
+            match g.get(k) {
+                Err(BlobError::NotFound) => {}
+                r => return r,
+            }
+        }
+        self.main.get(k)
+    }
+
+    pub fn remove<K: Serialize>(&mut self, k: &K) -> Result<(), BlobError> {
+        self.main.remove(k)?;
+        if let Some(g) = &mut self.grow {
+            g.remove(k)?;
+        }
+        self.move_next()
+    }
+
+    /// Every blob, those still in main then those in grow
+    pub fn iter_blobs(&self) -> impl Iterator<Item = Result<Blob, BlobError>> + '_ {
+        let grow = self.grow.iter().flat_map(|g| g.iter_blobs());
+        self.main.iter_blobs().chain(grow)
+    }
+
+    pub fn iter<K, V>(&self) -> impl Iterator<Item = Result<(K, V), BlobError>> + '_
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        self.bincode_blobs()
+            .map(|r| r.and_then(|b| Ok((b.get_k()?, b.get_v()?))))
+    }
+
+    pub fn keys<K: DeserializeOwned>(&self) -> impl Iterator<Item = Result<K, BlobError>> + '_ {
+        self.bincode_blobs().map(|r| r.and_then(|b| b.get_k()))
+    }
+
+    // every blob, or only CodecMismatch if they are not bincode to decode
+    fn bincode_blobs(&self) -> impl Iterator<Item = Result<Blob, BlobError>> + '_ {
+        let mismatch = self.main.check_bincode().err();
+        let n = if mismatch.is_some() { 0 } else { usize::MAX };
+        mismatch
+            .map(Err)
+            .into_iter()
+            .chain(self.iter_blobs().take(n))
+    }
+
+    /// Moves everything left in main over now, rather than a few buckets at a time
+    pub fn finish_move(&mut self) -> Result<(), BlobError> {
+        while self.grow.is_some() {
+            self.move_next()?;
+        }
+        Ok(())
+    }
+
+    fn start_move(&mut self, need: u64) -> Result<(), BlobError> {
+        let (bsize, nblocks) = bigger(&self.main, need);
+        self.grow = Some(fresh(&grow_name(&self.fname), bsize, nblocks, &self.main)?);
+        self.n_moved = 0;
+        Ok(())
+    }
+
+    // only called while moving. It replaces, so an insert of a key
+    // that is already in grow does not leave two of it
+    fn grow_insert(&mut self, blob: &Blob) -> Result<Option<Blob>, BlobError> {
+        loop {
+            let g = self.grow.as_mut().expect("grow_insert while not moving");
+            let need = match g.replace_blob(blob) {
+                Err(BlobError::NoRoom) => 0,
+                Err(BlobError::TooBig(n)) => n,
+                r => return r,
+            };
+            // grow filled up before main was empty,
+            // copy it into a bigger one again and carry on moving into that
+            let gname = grow_name(&self.fname);
+            let tmp = format!("{}.new", gname);
+            let mut g2 = rebuild(g, &tmp, need)?;
+            rename_synced(&mut g2, &tmp, &gname)?;
+            g2.renamed(&gname);
+            self.grow = Some(g2);
+        }
+    }
+
+    fn move_next(&mut self) -> Result<(), BlobError> {
+        if self.grow.is_none() {
+            return Ok(());
+        }
+        let end = (self.n_moved + MOVE_STEP).min(self.main.nblocks());
+        while self.n_moved < end {
+            for b in self.main.raw_blobs(self.n_moved)? {
+                // already in grow means moved before a crash, or put in new by an
+                // insert that did not get to take it out of main, so keep grow's
+                if !self.grow.as_ref().unwrap().has_blob(&b)? {
+                    self.grow_insert(&b)?;
+                }
+            }
+            self.main.clear_bucket(self.n_moved)?;
+            self.n_moved += 1;
+        }
+        if self.n_moved == self.main.nblocks() {
+            // rename is atomic, so a crash leaves either the old main
+            // with the grow file still next to it, or just the new main
+            let mut g = self.grow.take().unwrap();
+            // main's dead entries are in the log they share. A crash before
+            // the rename has them counted twice, that only makes gc come sooner.
+            g.add_dead(dead(&self.main))?;
+            rename_synced(&mut g, &grow_name(&self.fname), &self.fname)?;
+            g.renamed(&self.fname);
+            self.main = g;
+            self.n_moved = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vlog::{vlog_name, ENTRY_EXTRA};
+
+    fn clean(fname: &str) {
+        std::fs::remove_file(fname).ok();
+        std::fs::remove_file(grow_name(fname)).ok();
+        std::fs::remove_file(format!("{}.grow.new", fname)).ok();
+        for gen in 0..3 {
+            std::fs::remove_file(vlog_name(fname, gen)).ok();
+        }
+    }
+
+    fn value(i: i32) -> String {
+        format!("value number {}", i)
+    }
+
+    fn check(bm: &mut BlobMap, range: std::ops::Range<i32>) {
+        for i in range {
+            let s: String = bm.get(&i).unwrap().get_v().unwrap();
+            assert_eq!(s, value(i));
+        }
+    }
+
+    #[test]
+    pub fn test_grow_on_no_room() {
+        let fs = "test_data/bm_no_room";
+        clean(fs);
+        let mut bm = BlobMap::new(fs, 100, 2).unwrap();
+        for i in 0..300 {
+            bm.insert(i, value(i)).unwrap();
+            check(&mut bm, 0..i + 1);
+        }
+        assert!(bm.nblocks() > 2);
+        bm.finish_move().unwrap();
+        assert!(!Path::new(&grow_name(fs)).exists());
+        drop(bm);
+
+        let mut b2 = BlobMap::open(fs).unwrap();
+        check(&mut b2, 0..300);
+        assert!(matches!(b2.get(&300), Err(BlobError::NotFound)));
+        assert_eq!(b2.len(), 300);
+    }
+
+    #[test]
+    pub fn test_grow_on_too_big() {
+        let fs = "test_data/bm_too_big";
+        clean(fs);
+        let mut bm = BlobMap::new(fs, 100, 4).unwrap();
+        bm.insert(1, value(1)).unwrap();
+        let big = "x".repeat(1000);
+        bm.insert(2, &big).unwrap();
+        assert!(bm.block_size() >= 1000);
+        assert_eq!(bm.get(&2).unwrap().get_v::<String>().unwrap(), big);
+        check(&mut bm, 1..2);
+    }
+
+    #[test]
+    pub fn test_remove_while_moving() {
+        let fs = "test_data/bm_remove";
+        clean(fs);
+        let mut bm = BlobMap::new(fs, 100, 2).unwrap();
+        let mut i = 0;
+        while !bm.is_moving() {
+            bm.insert(i, value(i)).unwrap();
+            i += 1;
+        }
+        for j in (0..i).step_by(2) {
+            bm.remove(&j).unwrap();
+        }
+        assert_eq!(bm.len(), i as u64 / 2);
+        for j in 0..i {
+            match j % 2 {
+                0 => assert!(matches!(bm.get(&j), Err(BlobError::NotFound))),
+                _ => check(&mut bm, j..j + 1),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_resume_move() {
+        let fs = "test_data/bm_resume";
+        clean(fs);
+        let mut bm = BlobMap::new(fs, 100, 4).unwrap();
+        let mut i = 0;
+        while !bm.is_moving() {
+            bm.insert(i, value(i)).unwrap();
+            i += 1;
+        }
+        drop(bm);
+
+        // stopped half way, the grow file is picked up again
+        let mut b2 = BlobMap::open(fs).unwrap();
+        assert!(b2.is_moving());
+        check(&mut b2, 0..i);
+        b2.insert(i, value(i)).unwrap();
+        b2.finish_move().unwrap();
+        check(&mut b2, 0..i + 1);
+    }
+
+    #[test]
+    pub fn test_resume_after_insert_cut_short() {
+        let fs = "test_data/bm_resume_insert";
+        clean(fs);
+        let mut bm = BlobMap::new(fs, 100, 4).unwrap();
+        let mut i = 0;
+        while !bm.is_moving() {
+            bm.insert(i, value(i)).unwrap();
+            i += 1;
+        }
+        // an insert that put the new one in grow and stopped before main
+        for j in 0..i {
+            let b = Blob::from(&j, &j).unwrap();
+            bm.grow.as_mut().unwrap().replace_blob(&b).unwrap();
+        }
+        drop(bm);
+
+        let mut b2 = BlobMap::open(fs).unwrap();
+        for j in 0..i {
+            assert_eq!(b2.get(&j).unwrap().get_v::<i32>().unwrap(), j);
+        }
+        assert!(b2.len() > i as u64);
+        b2.finish_move().unwrap();
+        assert_eq!(b2.len(), i as u64);
+        for j in 0..i {
+            assert_eq!(b2.get(&j).unwrap().get_v::<i32>().unwrap(), j);
+        }
+    }
+
+    #[test]
+    pub fn test_insert_replaces_while_moving() {
+        let fs = "test_data/bm_replace";
+        clean(fs);
+        let mut bm = BlobMap::new(fs, 100, 2).unwrap();
+        let mut i = 0;
+        while !bm.is_moving() {
+            bm.insert(i, value(i)).unwrap();
+            i += 1;
+        }
+        // some of these are still in main, some already moved
+        for j in 0..i {
+            let old = bm.insert(j, j).unwrap().unwrap();
+            assert_eq!(old.get_v::<String>().unwrap(), value(j));
+        }
+        assert!(!bm.insert_if_absent(0, 7).unwrap());
+        assert!(bm.insert_if_absent(i, i).unwrap());
+        let b = bm.get_or_insert_with(i + 1, || i + 1).unwrap();
+        assert_eq!(b.get_v::<i32>().unwrap(), i + 1);
+        bm.finish_move().unwrap();
+        for j in 0..i + 2 {
+            assert_eq!(bm.get(&j).unwrap().get_v::<i32>().unwrap(), j);
+            bm.remove(&j).unwrap();
+            // a second copy would show up here
+            assert!(matches!(bm.get(&j), Err(BlobError::NotFound)));
+        }
+    }
+
+    #[test]
+    pub fn test_iter_while_moving() {
+        let fs = "test_data/bm_iter";
+        clean(fs);
+        let mut bm = BlobMap::new(fs, 100, 2).unwrap();
+        let mut i = 0;
+        while !bm.is_moving() {
+            bm.insert(i, value(i)).unwrap();
+            i += 1;
+        }
+        let mut all: Vec<(i32, String)> = bm.iter().map(|r| r.unwrap()).collect();
+        all.sort();
+        assert_eq!(all, (0..i).map(|j| (j, value(j))).collect::<Vec<_>>());
+        assert_eq!(bm.keys::<i32>().count(), i as usize);
+    }
+
+    #[test]
+    pub fn test_value_log_while_moving() {
+        let fs = "test_data/bm_vlog";
+        clean(fs);
+        // all the same length, so each takes one entry of the same size
+        let big = |i: i32, round: i32| format!("{:03}:{}", i, round).repeat(100);
+        let entry = ENTRY_EXTRA + bincode::serialize(&big(0, 0)).unwrap().len() as u64;
+        let mut bm = BlobMap::new(fs, 100, 8)
+            .unwrap()
+            .with_value_log(50)
+            .unwrap();
+        let mut i = 0;
+        while !bm.is_moving() {
+            bm.insert(i, big(i, 0)).unwrap();
+            i += 1;
+        }
+        let replace = |bm: &mut BlobMap, j| {
+            let old = bm.insert(j, big(j, 1)).unwrap().unwrap();
+            assert_eq!(old.get_v::<String>().unwrap(), big(j, 0));
+        };
+        replace(&mut bm, 0);
+        drop(bm);
+
+        // cut short, grow picks up main's log again
+        let mut bm = BlobMap::open(fs).unwrap();
+        assert!(bm.is_moving());
+        // some are still in main and some already moved
+        for j in 1..i {
+            replace(&mut bm, j);
+        }
+        bm.finish_move().unwrap();
+        for j in 0..i {
+            assert_eq!(bm.get(&j).unwrap().get_v::<String>().unwrap(), big(j, 1));
+        }
+        // one log, the live values in it once each and everything else dead
+        assert!(!Path::new(&vlog_name(&grow_name(fs), 0)).exists());
+        let (len, dead) = bm.value_log_stats().unwrap();
+        assert_eq!(len - dead, i as u64 * entry);
+        assert!(bm.gc_due());
+        bm.gc_value_log().unwrap();
+        assert_eq!(bm.value_log_stats().unwrap(), (i as u64 * entry, 0));
+        assert!(!Path::new(&vlog_name(fs, 0)).exists());
+        drop(bm);
+
+        let bm = BlobMap::open(fs).unwrap();
+        assert_eq!(bm.len(), i as u64);
+        let mut all: Vec<(i32, String)> = bm.iter().map(|r| r.unwrap()).collect();
+        all.sort();
+        assert_eq!(all, (0..i).map(|j| (j, big(j, 1))).collect::<Vec<_>>());
+    }
+}
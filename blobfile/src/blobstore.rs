@@ -1,237 +1,2588 @@
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
-use std::io::Seek;
 use std::io::SeekFrom;
+use std::io::{Cursor, Read, Seek, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
+use memmap2::Mmap;
+
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use crate::blob::{read_u64, write_u64, Blob};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::blob::{read_u64, record_crc, write_u64, Blob, LOGGED, RECORD_EXTRA};
+use crate::codec::{Bincode, CodecId};
 use crate::error::BlobError;
+use crate::iter::BlobIter;
+use crate::journal::{journal_name, write_at, Journal};
+use crate::vlog::{vlog_name, ValueLog, ENTRY_EXTRA};
+
+// header is magic, version, hseed, block_size, nblocks, elems, codec,
+// then for the value log its threshold (0 for none), generation and dead bytes
+// Files from before there was a magic have no crc on their records
+// either, they are turned away as not blob files. Anything that changes
+// the layout from here on bumps VERSION, and open_with reads the old one.
+const MAGIC: [u8; 8] = *b"BLOBFILE";
+const VERSION: u64 = 1;
+const COUNT_SIZE: u64 = 80;
+const ELEMS_POS: u64 = 40;
+const THRESHOLD_POS: u64 = 56;
+const GEN_POS: u64 = 64;
+const DEAD_POS: u64 = 72;
+// every block starts with where the next block of its bucket is, 0 for none
+const LINK_SIZE: u64 = 8;
+// overflow blocks a bucket may have before NoRoom tells the wrapper to grow
+const MAX_CHAIN: u64 = 2;
+
+// A record's klen of 0 marks a free section, so a key encoded as no
+// bytes at all, an empty string or () say, could never be read back
+fn check_key(blob: &Blob) -> Result<(), BlobError> {
+    match blob.k_bytes().is_empty() {
+        true => Err(BlobError::EmptyKey),
+        false => Ok(()),
+    }
+}
+
+fn corrupt(offset: u64, reason: &str) -> BlobError {
+    BlobError::Corrupt {
+        offset,
+        reason: reason.to_string(),
+    }
+}
+
+// Reads from a set place in the file without moving its cursor,
+// so any number of them can read through one &File at the same time
+struct ReadAt<'a> {
+    f: &'a File,
+    pos: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.f.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+// Blob::read only asks where it is, that is all this needs to do
+impl Seek for ReadAt<'_> {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        match to {
+            SeekFrom::Start(p) => self.pos = p,
+            SeekFrom::Current(0) => {}
+            _ => return Err(std::io::Error::other("ReadAt only seeks from the start")),
+        }
+        Ok(self.pos)
+    }
+}
+
+// Takes the advisory lock on the file, shared for a reader or all of it
+// for a writer. It is let go when the file is closed.
+fn lock(f: &File, shared: bool) -> Result<(), BlobError> {
+    // called through fs2 by name, std's File has methods of the same names
+    let res = match shared {
+        true => fs2::FileExt::try_lock_shared(f),
+        false => fs2::FileExt::try_lock_exclusive(f),
+    };
+    res.map_err(|e| {
+        if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+            return BlobError::Locked;
+        }
+        e.into()
+    })
+}
+
+/// When the store's file is synced to disk. The journal, when there
+/// is one, syncs for itself whatever this is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// After every insert or remove, and once for a whole batch
+    EveryOp,
+    /// At the end of each `write_batch`, single changes are left to the OS
+    EveryBatch,
+    /// Only when `sync` is called
+    Never,
+}
+
+/// How the space in one bucket is used
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketStats {
+    /// the bucket's own block plus any overflow blocks
+    pub blocks: u64,
+    pub live: u64,
+    /// bytes taken by records, headers included
+    pub used_bytes: u64,
+    /// bytes in free sections, their markers included
+    pub free_bytes: u64,
+    pub free_sections: u64,
+    pub largest_free: u64,
+}
 
-const COUNT_SIZE: u64 = 32;
+impl BucketStats {
+    /// 0 when all the free space is in one piece,
+    /// nearer 1 the more it is split into small holes
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free as f64 / self.free_bytes as f64
+    }
+}
 
 /// This blob store will act as one half of the hashmap
 /// as with the hashmap wrap this in something to make growing work
+///
+/// When a bucket's block is full an overflow block is added to the end of
+/// the file and linked from it, up to max_chain of them per bucket.
+///
+/// With a journal (see `with_journal`) each insert or remove either
+/// happens completely or not at all, even if the program dies half way.
+///
+/// With `with_mmap` (or `open_read_only`) reads come from a map of the file
+/// rather than a read call each, and `get_ref` can hand back the value
+/// without copying it. Writes still go to the file, the map shares its pages.
+///
+/// The file is locked while it is open, by one writer or any number of
+/// readers, anything else trying gets Locked. The lock is only advisory,
+/// it keeps BlobStores apart, not other programs.
+///
+/// With `with_value_log` values over a threshold go in a log next to the
+/// store and the bucket only keeps where, so they can be any size. Space
+/// left by values replaced or removed is taken back by `gc_value_log`.
+/// It rewrites the whole log, so it is never run as part of a change:
+/// call it when `gc_due` says half the log is dead.
 pub struct BlobStore {
+    fname: String,
     file: File,
+    flen: u64,
     hseed: u64,
     block_size: u64,
     nblocks: u64,
     elems: u64,
+    codec: u64,
+    max_chain: u64,
+    journal: Option<Journal>,
+    depth: u32,               // how deep in atomic() we are
+    fail_after: Option<u64>, // bytes to write before a pretend crash, for tests
+    map: Option<Mmap>,
+    read_only: bool,
+    sync: SyncPolicy,
+    syncs: u64, // times the file was synced, for tests
+    vlog: Option<ValueLog>,
+    // the value log is named after this, the store's own name
+    // unless it shares another store's log
+    log_base: String,
+    vlog_threshold: u64,
+    vlog_gen: u64,
+    vlog_dead: u64, // bytes in the log no record points at any more
+    // set for the handles of a SharedBlobStore, see with_header
+    header: Option<Arc<Mutex<SharedHeader>>>,
+}
+
+/// The parts of the header a change to any bucket may change: the count,
+/// the file's length as blocks are added, and the dead bytes in the value
+/// log. SharedBlobStore's handles keep them here, behind one small lock.
+#[derive(Debug)]
+pub(crate) struct SharedHeader {
+    elems: u64,
+    flen: u64,
+    vlog_dead: u64,
+}
+
+impl SharedHeader {
+    pub(crate) fn elems(&self) -> u64 {
+        self.elems
+    }
 }
 
 impl BlobStore {
     pub fn new(fname: &str, block_size: u64, nblocks: u64) -> Result<Self, BlobError> {
+        Self::create(fname, block_size, nblocks, Bincode::ID)
+    }
+
+    /// codec is the CodecId of whatever the keys and values are encoded with
+    pub(crate) fn create(
+        fname: &str,
+        block_size: u64,
+        nblocks: u64,
+        codec: u64,
+    ) -> Result<Self, BlobError> {
         let hseed = rand::random::<u64>();
-        // create_file
+        // made under a name of its own and only linked in once the header
+        // is there, so an open racing this never finds a half made file
+        let tmp = format!("{}.{}.new", fname, rand::random::<u64>());
         let mut ff = OpenOptions::new()
             .create_new(true)
             .write(true)
             .read(true) // the holder of this may want to read
-            .open(fname)?;
+            .open(&tmp)?;
+        let flen = COUNT_SIZE + block_size * nblocks;
+        let res = Self::write_new(&mut ff, hseed, block_size, nblocks, codec)
+            // fails if the name is taken, same as create_new would
+            .and_then(|_| Ok(std::fs::hard_link(&tmp, fname)?));
+        std::fs::remove_file(&tmp).ok();
+        res?;
+        Ok({
+            BlobStore {
+                fname: fname.to_string(),
+                hseed,
+                file: ff,
+                flen,
+                block_size,
+                nblocks,
+                elems: 0,
+                codec,
+                max_chain: MAX_CHAIN,
+                journal: None,
+                depth: 0,
+                fail_after: None,
+                map: None,
+                read_only: false,
+                sync: SyncPolicy::EveryBatch,
+                syncs: 0,
+                vlog: None,
+                log_base: fname.to_string(),
+                vlog_threshold: 0,
+                vlog_gen: 0,
+                vlog_dead: 0,
+                header: None,
+            }
+        })
+    }
 
-        let f = &mut ff;
-        f.set_len(COUNT_SIZE + block_size * nblocks)?;
+    // the header and empty buckets of a new store, locked and synced
+    fn write_new(
+        f: &mut File,
+        hseed: u64,
+        block_size: u64,
+        nblocks: u64,
+        codec: u64,
+    ) -> Result<(), BlobError> {
+        lock(f, false)?;
+        let flen = COUNT_SIZE + block_size * nblocks;
+        f.set_len(flen)?;
         f.seek(SeekFrom::Start(0))?;
+        f.write_all(&MAGIC)?;
+        write_u64(f, VERSION)?;
         write_u64(f, hseed)?;
         write_u64(f, block_size)?;
         write_u64(f, nblocks)?;
         write_u64(f, 0)?; // 0 elems in new store
+        write_u64(f, codec)?;
+        write_u64(f, 0)?; // no value log until asked for
+        write_u64(f, 0)?;
+        write_u64(f, 0)?;
 
         // mark beginnings of each block to show empty
         for x in 0..nblocks {
             f.seek(SeekFrom::Start(COUNT_SIZE + x * block_size))?;
+            write_u64(f, 0)?; // no overflow block
             write_u64(f, 0)?; // Key length 0 means no item
-            write_u64(f, block_size - 16)?;
+            write_u64(f, block_size - LINK_SIZE - 16)?;
         }
-        Ok({
-            BlobStore {
-                hseed,
-                file: ff,
-                block_size,
-                nblocks,
-                elems: 0,
-            }
-        })
+        Ok(f.sync_data()?)
     }
 
     pub fn open(fname: &str) -> Result<Self, BlobError> {
-        let mut ff = OpenOptions::new().write(true).read(true).open(fname)?;
+        Self::open_with(fname, fname, false)
+    }
+
+    /// Opens the store mapped and only for reading, other readers can have
+    /// it open too but not a writer. Anything that
+    /// would change it gives ReadOnly
+    pub fn open_read_only(fname: &str) -> Result<Self, BlobError> {
+        Self::open_with(fname, fname, true)?.with_mmap()
+    }
+
+    // a writer gets the file to itself, any number of readers can share it
+    fn open_with(fname: &str, log_base: &str, read_only: bool) -> Result<Self, BlobError> {
+        let mut ff = OpenOptions::new().write(!read_only).read(true).open(fname)?;
+        // before anything is read, the journal may be about to be rolled back
+        lock(&ff, read_only)?;
+        let flen = ff.metadata()?.len();
+        if flen < COUNT_SIZE {
+            return Err(corrupt(0, "too short to be a blob file"));
+        }
+        let mut magic = [0u8; 8];
+        ff.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(corrupt(0, "not a blob file"));
+        }
+        let version = read_u64(&mut ff)?;
+        if version != VERSION {
+            let reason = format!("file is version {}, can only read {}", version, VERSION);
+            return Err(corrupt(8, &reason));
+        }
+        // a journal left behind means the store was in use with one,
+        // undo whatever change was cut short and keep using it
+        let jname = journal_name(fname);
+        let journal = match Path::new(&jname).exists() {
+            // can not undo it without writing, and what is there may be half done
+            true if read_only => {
+                if std::fs::metadata(&jname)?.len() > 0 {
+                    return Err(corrupt(0, "unfinished change in the journal, open writable"));
+                }
+                None
+            }
+            true => {
+                let mut j = Journal::open(&jname)?;
+                j.rollback(&mut ff, &mut None)?;
+                Some(j)
+            }
+            false => None,
+        };
+        // the rollback may have cut an overflow block back off
+        let flen = ff.metadata()?.len();
         let f = &mut ff;
-        f.seek(SeekFrom::Start(0))?;
+        f.seek(SeekFrom::Start(16))?;
         let hseed = read_u64(f)?;
         let block_size = read_u64(f)?;
         let nblocks = read_u64(f)?;
         let elems = read_u64(f)?;
+        let codec = read_u64(f)?;
+        let vlog_threshold = read_u64(f)?;
+        let vlog_gen = read_u64(f)?;
+        let vlog_dead = read_u64(f)?;
+        // overflow blocks come after the buckets' own ones
+        let size_ok = block_size >= LINK_SIZE + 16
+            && block_size
+                .checked_mul(nblocks)
+                .is_some_and(|n| n + COUNT_SIZE <= flen)
+            && (flen - COUNT_SIZE).is_multiple_of(block_size);
+        if nblocks == 0 || !size_ok {
+            return Err(corrupt(24, "block size and count do not match the file"));
+        }
+        let vlog = match vlog_threshold {
+            0 => None,
+            _ => {
+                if !read_only {
+                    // a gc cut short leaves the next log, one that finished the last
+                    std::fs::remove_file(vlog_name(log_base, vlog_gen + 1)).ok();
+                    if vlog_gen > 0 {
+                        std::fs::remove_file(vlog_name(log_base, vlog_gen - 1)).ok();
+                    }
+                }
+                Some(ValueLog::open(&vlog_name(log_base, vlog_gen), read_only)?)
+            }
+        };
         Ok(BlobStore {
+            fname: fname.to_string(),
             hseed,
             file: ff,
+            flen,
             block_size,
             nblocks,
             elems,
+            codec,
+            max_chain: MAX_CHAIN,
+            journal,
+            depth: 0,
+            fail_after: None,
+            map: None,
+            read_only,
+            sync: SyncPolicy::EveryBatch,
+            syncs: 0,
+            vlog,
+            log_base: log_base.to_string(),
+            vlog_threshold,
+            vlog_gen,
+            vlog_dead,
+            header: None,
         })
     }
 
-    pub fn new_or_open(fname: &str, bsize: u64, nblocks: u64) -> Result<Self, BlobError> {
-        Self::new(fname, bsize, nblocks).or_else(|_| Self::open(fname))
+    /// Another handle on the same open file that can only read, with
+    /// positional reads. It shares this one's lock rather than taking its own.
+    pub(crate) fn reader(&self) -> Result<BlobStore, BlobError> {
+        Ok(BlobStore {
+            fname: self.fname.clone(),
+            file: self.file.try_clone()?,
+            flen: self.flen,
+            hseed: self.hseed,
+            block_size: self.block_size,
+            nblocks: self.nblocks,
+            elems: self.elems,
+            codec: self.codec,
+            max_chain: self.max_chain,
+            journal: None,
+            depth: 0,
+            fail_after: None,
+            map: None,
+            read_only: true,
+            sync: SyncPolicy::Never,
+            syncs: 0,
+            vlog: self.vlog.as_ref().map(|l| l.try_clone()).transpose()?,
+            log_base: self.log_base.clone(),
+            vlog_threshold: self.vlog_threshold,
+            vlog_gen: self.vlog_gen,
+            vlog_dead: self.vlog_dead,
+            header: None,
+        })
     }
 
-    pub fn inc_elems(&mut self, n: i32) -> Result<(), BlobError> {
-        if n > 0 {
-            self.elems += n as u64;
-        } else {
-            let n2 = (-n) as u64;
-            if self.elems > n2 {
-                self.elems -= n2;
+    /// Puts the header fields any change may touch behind a lock, for
+    /// handles from `writer` to share with this one
+    pub(crate) fn share_header(&mut self) -> Arc<Mutex<SharedHeader>> {
+        let h = Arc::new(Mutex::new(SharedHeader {
+            elems: self.elems,
+            flen: self.flen,
+            vlog_dead: self.vlog_dead,
+        }));
+        self.header = Some(h.clone());
+        h
+    }
+
+    /// Another handle on the same open file, for a change to one bucket
+    /// while others change other buckets through handles of their own.
+    /// They share header through its lock. No journal, an undo journal
+    /// can only ever cover one change at a time.
+    pub(crate) fn writer(
+        &self,
+        header: &Arc<Mutex<SharedHeader>>,
+        sync: SyncPolicy,
+    ) -> Result<BlobStore, BlobError> {
+        let mut w = self.reader()?;
+        w.read_only = false;
+        w.sync = sync;
+        w.load_header(&header.lock().unwrap())?;
+        w.header = Some(header.clone());
+        Ok(w)
+    }
+
+    /// Brings this handle's header fields and value log up to
+    /// what the handles it shares them with have done
+    pub(crate) fn load_shared(&mut self) -> Result<(), BlobError> {
+        if let Some(h) = self.header.clone() {
+            self.load_header(&h.lock().unwrap())?;
+        }
+        if let Some(l) = &mut self.vlog {
+            l.catch_up()?;
+        }
+        Ok(())
+    }
+
+    /// Stops sharing the header, once nothing else has it
+    pub(crate) fn unshare_header(&mut self) {
+        self.header = None;
+    }
+
+    fn load_header(&mut self, h: &SharedHeader) -> Result<(), BlobError> {
+        self.elems = h.elems;
+        self.vlog_dead = h.vlog_dead;
+        if self.flen != h.flen {
+            self.flen = h.flen;
+            if self.map.is_some() {
+                self.remap()?;
             }
         }
-        self.file.seek(SeekFrom::Start(24))?;
-        write_u64(&mut self.file, self.elems)?;
         Ok(())
     }
 
-    // does not remove if already there
-    pub fn insert_only<K: Serialize, V: Serialize>(&mut self, k: K, v: V) -> Result<(), BlobError> {
-        let blob = Blob::from(&k, &v)?;
-        if blob.len() > self.block_size {
-            // Let the wrapper make a file with a bigger group
-            return Err(BlobError::TooBig(blob.len()));
+    fn store_header(&self, h: &mut SharedHeader) {
+        h.elems = self.elems;
+        h.flen = self.flen;
+        h.vlog_dead = self.vlog_dead;
+    }
+
+    // Runs f with the header fields up to date and kept from any other
+    // handle sharing them until it is done, or just runs f if they are
+    // not shared. f must not get back here, the lock is not reentrant.
+    fn with_header<R, F>(&mut self, f: F) -> Result<R, BlobError>
+    where
+        F: FnOnce(&mut Self) -> Result<R, BlobError>,
+    {
+        let Some(h) = self.header.clone() else {
+            return f(self);
+        };
+        let mut h = h.lock().unwrap();
+        self.load_header(&h)?;
+        let res = f(self);
+        self.store_header(&mut h);
+        res
+    }
+
+    /// Opens a store that appends to the value log of the store at log_base
+    pub(crate) fn open_sharing_log(fname: &str, log_base: &str) -> Result<Self, BlobError> {
+        Self::open_with(fname, log_base, false)
+    }
+
+    // For a store taking over from's records, as BlobMap does while it
+    // moves them: it appends to the same log, so records pointing into it
+    // can be copied as they are. Neither may gc while they share it.
+    pub(crate) fn share_log(&mut self, from: &BlobStore) -> Result<(), BlobError> {
+        let Some(log) = &from.vlog else {
+            return Ok(());
+        };
+        self.vlog = Some(log.try_clone()?);
+        self.log_base = from.log_base.clone();
+        let (threshold, gen) = (from.vlog_threshold, from.vlog_gen);
+        self.atomic(|s| {
+            s.write_u64_at(THRESHOLD_POS, threshold)?;
+            s.write_u64_at(GEN_POS, gen)
+        })?;
+        self.vlog_threshold = threshold;
+        self.vlog_gen = gen;
+        Ok(())
+    }
+
+    // counts n more bytes of the log as dead, those of a store
+    // whose records this one has taken over
+    pub(crate) fn add_dead(&mut self, n: u64) -> Result<(), BlobError> {
+        self.atomic(|s| {
+            s.with_header(|s| {
+                s.vlog_dead += n;
+                s.write_u64_at(DEAD_POS, s.vlog_dead)
+            })
+        })
+    }
+
+    // After the file is renamed. A log of its own would have to be renamed
+    // too, BlobMap only ever renames stores sharing main's log or without one.
+    pub(crate) fn renamed(&mut self, fname: &str) {
+        if self.vlog.is_none() {
+            self.log_base = fname.to_string();
         }
+        self.fname = fname.to_string();
+    }
 
-        let bucket = blob.k_hash(self.hseed) % self.nblocks;
-        let f = &mut self.file;
-        let mut pos = f.seek(SeekFrom::Start(COUNT_SIZE + self.block_size * bucket))?;
-        // start each loop at beginning of an elem
-        // remember klen == 0 means empty section
-        loop {
-            if pos >= COUNT_SIZE + self.block_size * (bucket + 1) {
-                // reached end of data block
-                // consider other handlings but this will tell the wrapper tp make space
-                // another option is to overflow onto the end of the file.
-                return Err(BlobError::NoRoom);
-            }
-            let klen = read_u64(f)?;
-            let vlen = read_u64(f)?;
-            if klen == 0 && blob.len() < vlen {
-                f.seek(SeekFrom::Start(pos))?;
-                blob.out(f)?;
-                // add pointer immediatly after data ends
-                write_u64(f, 0)?;
-                write_u64(f, (vlen - blob.len()) - 16)?;
-                return Ok(());
+    /// Reads from a map of the file from now on
+    pub fn with_mmap(mut self) -> Result<Self, BlobError> {
+        self.remap()?;
+        Ok(self)
+    }
+
+    // the map has to cover the whole file, so this is redone when it grows
+    fn remap(&mut self) -> Result<(), BlobError> {
+        // safe as long as nobody else cuts the file short under us
+        self.map = Some(unsafe { Mmap::map(&self.file)? });
+        Ok(())
+    }
+
+    fn u64_at(&self, pos: u64) -> Result<u64, BlobError> {
+        match &self.map {
+            Some(m) => {
+                let mut c = Cursor::new(&m[..]);
+                c.set_position(pos);
+                read_u64(&mut c)
+            }
+            None => read_u64(&mut ReadAt { f: &self.file, pos }),
+        }
+    }
+
+    // the record at pos, which can not run past the end of its block
+    fn blob_at(&self, pos: u64) -> Result<Blob, BlobError> {
+        let max = self.block_end(pos) - pos;
+        match &self.map {
+            Some(m) => {
+                let mut c = Cursor::new(&m[..]);
+                c.set_position(pos);
+                Blob::read(&mut c, max)
             }
-            pos = f.seek(SeekFrom::Start(pos + 16 + klen + vlen))?;
+            None => Blob::read(&mut ReadAt { f: &self.file, pos }, max),
         }
     }
 
-    pub fn get<K: Serialize>(&mut self, k: &K) -> Result<Blob, BlobError> {
-        let s_blob = Blob::from(k, &0)?;
-        let bucket = s_blob.k_hash(self.hseed) % self.nblocks;
-        let f = &mut self.file;
+    // where the block pos is in ends
+    fn block_end(&self, pos: u64) -> u64 {
+        COUNT_SIZE + self.block_size * ((pos - COUNT_SIZE) / self.block_size + 1)
+    }
 
-        let mut pos = f.seek(SeekFrom::Start(COUNT_SIZE + self.block_size * bucket))?;
-        // start each loop in at front of block elem
-        loop {
-            if pos >= COUNT_SIZE + self.block_size * (bucket + 1) {
-                return Err(BlobError::NotFound);
+    /// Turns on the journal, kept next to the store as "<fname>.journal".
+    /// Once on it stays on, open finds the journal and uses it again.
+    pub fn with_journal(mut self) -> Result<Self, BlobError> {
+        if self.journal.is_none() {
+            self.journal = Some(Journal::create(&journal_name(&self.fname))?);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn has_journal(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Turns the journal off and removes its file
+    pub fn without_journal(mut self) -> Result<Self, BlobError> {
+        if self.journal.take().is_some() {
+            std::fs::remove_file(journal_name(&self.fname))?;
+        }
+        Ok(self)
+    }
+
+    // every write to the store after new goes through here,
+    // so the journal gets what was there first
+    fn write(&mut self, pos: u64, data: &[u8]) -> Result<(), BlobError> {
+        if let Some(j) = &mut self.journal {
+            j.record(&self.file, pos, data.len() as u64, &mut self.fail_after)?;
+        }
+        write_at(&self.file, pos, data, &mut self.fail_after)
+    }
+
+    fn write_u64_at(&mut self, pos: u64, n: u64) -> Result<(), BlobError> {
+        self.write(pos, &n.to_le_bytes())
+    }
+
+    // writes a free section marker
+    fn write_free(&mut self, pos: u64, vlen: u64) -> Result<(), BlobError> {
+        let mut buf = Vec::with_capacity(16);
+        write_u64(&mut buf, 0)?;
+        write_u64(&mut buf, vlen)?;
+        self.write(pos, &buf)
+    }
+
+    fn write_blob(&mut self, pos: u64, blob: &Blob) -> Result<(), BlobError> {
+        let mut buf = Vec::with_capacity(blob.record_len() as usize);
+        blob.out(&mut buf)?;
+        self.write(pos, &buf)
+    }
+
+    // Runs one whole change. Once the outermost one is done the journal is
+    // emptied, or if it failed everything it wrote is put back
+    // and what it put in the value log is counted as dead.
+    fn atomic<R, F>(&mut self, f: F) -> Result<R, BlobError>
+    where
+        F: FnOnce(&mut Self) -> Result<R, BlobError>,
+    {
+        if self.read_only {
+            return Err(BlobError::ReadOnly);
+        }
+        let appended = self.vlog.as_ref().map_or(0, |l| l.appended());
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        if self.depth > 0 {
+            return res;
+        }
+        let mut res = res;
+        if self.journal.is_some() {
+            if res.is_ok() {
+                // if it can not be made to stick, undo it like any other failure
+                if let Err(e) = self.commit() {
+                    res = Err(e);
+                }
             }
-            let b = Blob::read(f)?;
-            if b.key_match(&s_blob) {
-                return Ok(b);
+            if let Err(e) = res {
+                res = match self.rollback() {
+                    Ok(()) => Err(e),
+                    // keep why the change failed as well as why undoing it did.
+                    // Nothing more is written, the next open rolls back.
+                    Err(r) => {
+                        return Err(BlobError::RollbackFailed {
+                            error: Box::new(e),
+                            rollback: Box::new(r),
+                        })
+                    }
+                };
             }
-            pos += b.len();
+        } else if res.is_ok() && self.sync == SyncPolicy::EveryOp {
+            // clearing the journal in commit syncs already
+            self.sync()?;
         }
+        if res.is_err() {
+            self.count_orphans(appended);
+        }
+        res
     }
 
-    pub fn remove<K: Serialize>(&mut self, k: &K) -> Result<(), BlobError> {
-        let s_blob = Blob::from(k, &0)?;
-        let bucket = s_blob.k_hash(self.hseed) % self.nblocks;
-        let f = &mut self.file;
+    // only called with a journal, once the outermost atomic() is done
+    fn commit(&mut self) -> Result<(), BlobError> {
+        // what the change put in the value log has to be on disk
+        // before the way back to records not pointing at it is gone
+        if let Some(l) = &mut self.vlog {
+            l.sync()?;
+        }
+        self.journal.as_mut().unwrap().clear(&mut self.file)
+    }
 
-        let mut pos = f.seek(SeekFrom::Start(COUNT_SIZE + self.block_size * bucket))?;
-        //start each loop in at front of block elem
-        let b_end = COUNT_SIZE + self.block_size * (bucket + 1);
-        loop {
-            if pos >= b_end {
-                return Ok(());
-            }
-            let b = Blob::read(f)?;
-            if b.key_match(&s_blob) {
-                let l = b.len();
-                //check if next block is empty, then we can join them
-
-                if pos + l < b_end {
-                    if read_u64(f)? == 0 {
-                        let nlen = read_u64(f)?;
-                        f.seek(SeekFrom::Start(pos))?;
-                        write_u64(f, 0)?;
-                        write_u64(f, l + nlen + 16)?;
-                        return Ok(());
-                    }
+    fn rollback(&mut self) -> Result<(), BlobError> {
+        let j = self.journal.as_mut().unwrap();
+        j.rollback(&mut self.file, &mut self.fail_after)?;
+        // any overflow block it added is gone again
+        self.flen = self.file.metadata()?.len();
+        if self.map.is_some() {
+            self.remap()?;
+        }
+        self.elems = self.u64_at(ELEMS_POS)?;
+        self.vlog_dead = self.u64_at(DEAD_POS)?;
+        // with a journal changes go one at a time, so what is
+        // on disk now is what every sharer should see
+        if let Some(h) = &self.header {
+            self.store_header(&mut h.lock().unwrap());
+        }
+        Ok(())
+    }
+
+    /// Values longer than threshold bytes go in the value log from now on,
+    /// "<fname>.vlog.N" next to the store. It is kept in the header, so open
+    /// finds the log again. Values already in the store stay where they are.
+    pub fn with_value_log(mut self, threshold: u64) -> Result<Self, BlobError> {
+        // 0 in the header means no log
+        let threshold = threshold.max(1);
+        if self.vlog.is_none() {
+            // made before the header says so, a crash between leaves an unused file
+            self.vlog = Some(ValueLog::create(&vlog_name(&self.log_base, self.vlog_gen))?);
+        }
+        self.atomic(|s| s.write_u64_at(THRESHOLD_POS, threshold))?;
+        self.vlog_threshold = threshold;
+        Ok(self)
+    }
+
+    /// The value log's length and how much of it is dead, None without one
+    pub fn value_log_stats(&self) -> Option<(u64, u64)> {
+        self.vlog.as_ref().map(|l| (l.len(), self.vlog_dead))
+    }
+
+    /// True once half the value log is dead, the time to run `gc_value_log`
+    pub fn gc_due(&self) -> bool {
+        match &self.vlog {
+            Some(l) => self.vlog_dead > 0 && self.vlog_dead * 2 >= l.len(),
+            None => false,
+        }
+    }
+
+    /// Copies the values still pointed at into a new value log, points their
+    /// records at the copies and drops the old log. The records and the
+    /// generation in the header change in one go, so with a journal a crash
+    /// leaves either the old log in use or the new one, never a mix.
+    pub fn gc_value_log(&mut self) -> Result<(), BlobError> {
+        if self.read_only {
+            return Err(BlobError::ReadOnly);
+        }
+        if self.vlog.is_none() {
+            return Ok(());
+        }
+        let gen = self.vlog_gen + 1;
+        let mut log = ValueLog::create(&vlog_name(&self.log_base, gen))?;
+        let mut moves = Vec::new();
+        for bucket in 0..self.nblocks {
+            for (pos, b) in self.raw_bucket(bucket)? {
+                if b.log_pointer().is_some() {
+                    let full = self.resolve(b)?;
+                    let at = log.append(full.v_bytes(), &mut self.fail_after)?;
+                    moves.push((pos, full.pointing_to(at)));
                 }
-                f.seek(SeekFrom::Start(pos))?;
-                write_u64(f, 0)?;
-                write_u64(f, l - 16)?;
+            }
+        }
+        log.sync()?;
+        let res = self.atomic(|s| {
+            // same length as what was there, so each goes back in its place
+            for (pos, b) in &moves {
+                s.write_blob(*pos, b)?;
+            }
+            s.write_u64_at(GEN_POS, gen)?;
+            s.with_header(|s| {
+                s.vlog_dead = 0;
+                s.write_u64_at(DEAD_POS, 0)
+            })
+        });
+        if let Err(e) = res {
+            std::fs::remove_file(vlog_name(&self.log_base, gen)).ok();
+            return Err(e);
+        }
+        let old = vlog_name(&self.log_base, self.vlog_gen);
+        self.vlog = Some(log);
+        self.vlog_gen = gen;
+        std::fs::remove_file(old)?;
+        Ok(())
+    }
 
-                return Ok(());
+    // Moves a value over the threshold out to the value log, giving back
+    // the record to keep in the bucket in its place
+    fn log_big(&mut self, blob: &Blob) -> Result<Option<Blob>, BlobError> {
+        if !self.goes_to_log(blob) {
+            return Ok(None);
+        }
+        let pos = match self.header {
+            // only the room is taken under the lock, the value is
+            // written alongside those of other handles
+            Some(_) => {
+                let len = blob.v_bytes().len() as u64;
+                let pos = self.with_header(|s| s.vlog.as_mut().unwrap().reserve(len))?;
+                let log = self.vlog.as_mut().unwrap();
+                log.write_entry(pos, blob.v_bytes(), &mut self.fail_after)?;
+                pos
             }
-            pos += b.len();
+            None => {
+                let log = self.vlog.as_mut().unwrap();
+                log.append(blob.v_bytes(), &mut self.fail_after)?
+            }
+        };
+        Ok(Some(blob.pointing_to(pos)))
+    }
+
+    fn goes_to_log(&self, blob: &Blob) -> bool {
+        self.vlog.is_some()
+            && blob.log_pointer().is_none()
+            && blob.v_bytes().len() as u64 > self.vlog_threshold
+    }
+
+    // Entries a failed change put in the value log have nothing pointing
+    // at them, so they count as dead for gc to take back. Only when gc is
+    // due depends on the count, so if it can not be written it is let go.
+    fn count_orphans(&mut self, appended: u64) {
+        let now = self.vlog.as_ref().map_or(appended, |l| l.appended());
+        if now > appended {
+            self.add_dead(now - appended).ok();
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // the blob with its value read from the value log if that is where it is
+    fn resolve(&self, b: Blob) -> Result<Blob, BlobError> {
+        let Some((pos, len)) = b.log_pointer() else {
+            return Ok(b);
+        };
+        let v = match &self.vlog {
+            Some(log) => log.read(pos, len)?,
+            None => {
+                return Err(corrupt(
+                    0,
+                    "record points into a value log there is none of",
+                ))
+            }
+        };
+        Ok(b.with_value(v))
+    }
 
-    #[test]
-    pub fn test_create_file() {
-        let fs = "test_data/create_file";
-        std::fs::remove_file(fs).ok();
-        let mut bs = BlobStore::new(fs, 1000, 10).unwrap();
-        let blocksize = bs.block_size;
-        let mut b2 = BlobStore::open(fs).unwrap();
-        assert_eq!(b2.block_size, blocksize);
+    // counts the log entry of a record being replaced or removed as dead
+    fn dead_value(&mut self, b: &Blob) -> Result<(), BlobError> {
+        if let Some((_, len)) = b.log_pointer() {
+            self.with_header(|s| {
+                s.vlog_dead += len + ENTRY_EXTRA;
+                s.write_u64_at(DEAD_POS, s.vlog_dead)
+            })?;
+        }
+        Ok(())
+    }
 
-        b2.insert_only("fish", "so long and thanks for all teh fish")
-            .unwrap();
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync
+    }
 
-        bs.insert_only(55, "hello people").unwrap();
-        bs.insert_only("green", "Another really long data thing")
-            .unwrap();
-        bs.insert_only("sandwich", vec!["young", "old", "not_sure"])
-            .unwrap();
+    pub fn set_sync_policy(&mut self, sync: SyncPolicy) {
+        self.sync = sync;
+    }
 
-        //let blob = bs.get(&55).unwrap();
-        assert_eq!(
-            bs.get(&55).unwrap().get_v::<String>().unwrap(),
-            "hello people".to_string()
-        );
-        assert_eq!(
-            bs.get(&"green").unwrap().get_v::<String>().unwrap(),
-            "Another really long data thing".to_string()
-        );
-        let vback: Vec<String> = bs.get(&"sandwich").unwrap().get_v().unwrap();
-        assert_eq!(
-            vback,
-            vec![
-                "young".to_string(),
-                "old".to_string(),
-                "not_sure".to_string()
-            ]
-        );
+    /// Makes sure everything written so far is on disk, value log too
+    pub fn sync(&mut self) -> Result<(), BlobError> {
+        // the log first, so no record on disk points past what is there
+        if let Some(l) = &mut self.vlog {
+            l.sync()?;
+        }
+        self.file.sync_data()?;
+        self.syncs += 1;
+        Ok(())
+    }
 
-        bs.remove(&"green").unwrap();
+    pub fn new_or_open(fname: &str, bsize: u64, nblocks: u64) -> Result<Self, BlobError> {
+        Self::new(fname, bsize, nblocks).or_else(|_| Self::open(fname))
+    }
 
-        assert!(bs.get(&"green").is_err());
-        assert!(bs.get(&55).is_ok());
+    pub fn inc_elems(&mut self, n: i32) -> Result<(), BlobError> {
+        self.atomic(|s| {
+            s.with_header(|s| {
+                if n > 0 {
+                    s.elems += n as u64;
+                } else {
+                    let n2 = (-n) as u64;
+                    s.elems = s.elems.saturating_sub(n2);
+                }
+                s.write_u64_at(ELEMS_POS, s.elems)
+            })
+        })
     }
 
-    #[test]
-    pub fn test_reread() {
-        std::fs::remove_file("test_data/bs_reread").ok();
-        let bs = BlobStore::new("test_data/bs_reread", 1000, 10).unwrap();
-        drop(bs);
-        let b2 = BlobStore::open("test_data/bs_reread").unwrap();
-        assert_eq!(b2.block_size, 1000);
+    pub fn fname(&self) -> &str {
+        &self.fname
+    }
+
+    /// The seed keys are hashed with to pick their bucket
+    pub fn seed(&self) -> u64 {
+        self.hseed
+    }
+
+    /// Blocks in the file, the buckets' own and every overflow block
+    pub fn total_blocks(&self) -> u64 {
+        (self.flen - COUNT_SIZE) / self.block_size
+    }
+
+    /// Length of the file, overflow blocks and all
+    pub(crate) fn flen(&self) -> u64 {
+        self.flen
+    }
+
+    pub(crate) fn vlog_gen(&self) -> u64 {
+        self.vlog_gen
+    }
+
+    // For a second handle on a file some other handle changes: the file
+    // may be longer, and after a gc the value log is another one
+    pub(crate) fn catch_up(&mut self, w: &BlobStore) -> Result<(), BlobError> {
+        // handles adding blocks side by side may finish in any order
+        self.flen = self.flen.max(w.flen);
+        if self.vlog_gen != w.vlog_gen {
+            self.vlog = w.vlog.as_ref().map(|l| l.try_clone()).transpose()?;
+            self.vlog_gen = w.vlog_gen;
+        }
+        Ok(())
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn nblocks(&self) -> u64 {
+        self.nblocks
+    }
+
+    /// Number of pairs in the store, kept in the header
+    pub fn len(&self) -> u64 {
+        self.elems
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elems == 0
+    }
+
+    /// Bytes there are for records, headers included, overflow blocks too
+    pub fn capacity(&self) -> u64 {
+        (self.flen - COUNT_SIZE) / self.block_size * (self.block_size - LINK_SIZE)
+    }
+
+    /// The CodecId the file was made with
+    pub fn codec(&self) -> u64 {
+        self.codec
+    }
+
+    // the generic methods here encode with bincode, so they must not be
+    // used on a file written by a TypedBlobStore with some other codec
+    pub(crate) fn blob_from<K: Serialize, V: Serialize>(
+        &self,
+        k: &K,
+        v: &V,
+    ) -> Result<Blob, BlobError> {
+        self.check_bincode()?;
+        Ok(Blob::from(k, v)?)
+    }
+
+    pub(crate) fn check_bincode(&self) -> Result<(), BlobError> {
+        self.check_codec(Bincode::ID)
+    }
+
+    fn check_codec(&self, codec: u64) -> Result<(), BlobError> {
+        if self.codec != codec {
+            return Err(BlobError::CodecMismatch {
+                found: self.codec,
+                expected: codec,
+            });
+        }
+        Ok(())
+    }
+
+    /// The blob for a key already encoded with codec, for tools that
+    /// read the codec from the header and encode keys themselves.
+    /// Fails with CodecMismatch if the file is not that codec.
+    pub fn get_raw(&self, key: &[u8], codec: u64) -> Result<Blob, BlobError> {
+        self.check_codec(codec)?;
+        self.get_blob(&Blob::from_bytes(key.to_vec(), Vec::new()))
+    }
+
+    /// Puts in a pair already encoded with codec, returns the blob it replaced
+    pub fn insert_raw(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        codec: u64,
+    ) -> Result<Option<Blob>, BlobError> {
+        self.check_codec(codec)?;
+        self.replace_blob(&Blob::from_bytes(key.to_vec(), value.to_vec()))
+    }
+
+    /// Takes out the pair for a key already encoded with codec
+    pub fn remove_raw(&mut self, key: &[u8], codec: u64) -> Result<Option<Blob>, BlobError> {
+        self.check_codec(codec)?;
+        self.remove_blob(&Blob::from_bytes(key.to_vec(), Vec::new()))
+    }
+
+    pub fn max_chain(&self) -> u64 {
+        self.max_chain
+    }
+
+    /// How many overflow blocks a bucket may have, 0 turns overflow off.
+    /// It is only for this handle and not kept in the file,
+    /// open starts again from the default.
+    pub fn set_max_chain(&mut self, n: u64) {
+        self.max_chain = n;
+    }
+
+    /// Counts the pairs by reading every bucket, to check len against
+    pub fn verify_count(&self) -> Result<u64, BlobError> {
+        let mut n = 0;
+        for bucket in 0..self.nblocks {
+            n += self.raw_bucket(bucket)?.len() as u64;
+        }
+        Ok(n)
+    }
+
+    pub(crate) fn bucket_of(&self, blob: &Blob) -> u64 {
+        blob.k_hash(self.hseed) % self.nblocks
+    }
+
+    // does not remove if already there
+    pub fn insert_only<K: Serialize, V: Serialize>(&mut self, k: K, v: V) -> Result<(), BlobError> {
+        let blob = self.blob_from(&k, &v)?;
+        self.insert_blob(&blob)
+    }
+
+    /// Puts an already encoded blob in, used when moving blobs between stores
+    pub(crate) fn insert_blob(&mut self, blob: &Blob) -> Result<(), BlobError> {
+        check_key(blob)?;
+        self.atomic(|s| {
+            let logged = s.log_big(blob)?;
+            s.place(logged.as_ref().unwrap_or(blob))?;
+            s.inc_elems(1)
+        })
+    }
+
+    // has to fit in an empty block, with room for a free marker after unless exact
+    fn check_size(&self, blob: &Blob) -> Result<(), BlobError> {
+        let room = self.block_size - LINK_SIZE;
+        if blob.record_len() != room && blob.record_len() + 16 > room {
+            // Let the wrapper make a file with a bigger group,
+            // it gets the block size that would have been needed
+            return Err(BlobError::TooBig(blob.record_len() + LINK_SIZE + 16));
+        }
+        Ok(())
+    }
+
+    // Puts the blob in the free section at pos if it fits, false if not.
+    // The free section is 16 + vlen long, the blob goes at the front
+    // and the new free marker (16 more) straight after it,
+    // unless the blob fills it exactly
+    fn fill(&mut self, pos: u64, vlen: u64, blob: &Blob) -> Result<bool, BlobError> {
+        if blob.record_len() <= vlen || blob.record_len() == vlen + 16 {
+            self.write_blob(pos, blob)?;
+            if blob.record_len() <= vlen {
+                // add pointer immediatly after data ends
+                self.write_free(pos + blob.record_len(), vlen - blob.record_len())?;
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // finds a free section for the blob and writes it there, the count is left alone
+    fn place(&mut self, blob: &Blob) -> Result<(), BlobError> {
+        self.place_or_compact(blob, true)
+    }
+
+    fn place_or_compact(&mut self, blob: &Blob, compact: bool) -> Result<(), BlobError> {
+        self.check_size(blob)?;
+        let bucket = self.bucket_of(blob);
+        let chain = self.chain(bucket)?;
+        // remember klen == 0 means empty section
+        for &block in &chain {
+            for (pos, klen, len) in self.sections(block)? {
+                if klen == 0 && self.fill(pos, len - 16, blob)? {
+                    return Ok(());
+                }
+            }
+        }
+        // reached end of the chain
+        // if the room is there but in holes too small, pull it together and try again
+        let st = self.bucket_stats(bucket)?;
+        if compact && st.free_sections > st.blocks && st.free_bytes >= blob.record_len() {
+            self.compact_bucket(bucket)?;
+            return self.place_or_compact(blob, false);
+        }
+        // otherwise overflow onto the end of the file
+        if (chain.len() as u64) <= self.max_chain {
+            let block = self.add_overflow(chain[chain.len() - 1])?;
+            // check_size made sure it fits an empty block
+            self.fill(block + LINK_SIZE, self.block_size - LINK_SIZE - 16, blob)?;
+            return Ok(());
+        }
+        // too long a chain, this will tell the wrapper to make space
+        Err(BlobError::NoRoom)
+    }
+
+    // puts a new empty block on the end of the file and links it after last
+    fn add_overflow(&mut self, last: u64) -> Result<u64, BlobError> {
+        let block = self.with_header(|s| {
+            let block = s.flen;
+            // so an undone change takes the block back off again
+            if let Some(j) = &mut s.journal {
+                j.record_len(&s.file, &mut s.fail_after)?;
+            }
+            s.file.set_len(block + s.block_size)?;
+            s.flen += s.block_size;
+            Ok(block)
+        })?;
+        if self.map.is_some() {
+            self.remap()?;
+        }
+        self.write_u64_at(block, 0)?;
+        self.write_free(block + LINK_SIZE, self.block_size - LINK_SIZE - 16)?;
+        self.write_u64_at(last, block)?;
+        Ok(block)
+    }
+
+    // where each block of the bucket starts, its own block first
+    fn chain(&self, bucket: u64) -> Result<Vec<u64>, BlobError> {
+        let first_overflow = COUNT_SIZE + self.block_size * self.nblocks;
+        let mut res = vec![COUNT_SIZE + self.block_size * bucket];
+        loop {
+            let last = res[res.len() - 1];
+            let next = self.u64_at(last)?;
+            if next == 0 {
+                return Ok(res);
+            }
+            if next < first_overflow
+                || !(next - COUNT_SIZE).is_multiple_of(self.block_size)
+                || next + self.block_size > self.flen
+                || res.contains(&next)
+            {
+                return Err(corrupt(last, "bad overflow link"));
+            }
+            res.push(next);
+        }
+    }
+
+    // pos, klen (0 for free) and length of each section in the block
+    fn sections(&self, block: u64) -> Result<Vec<(u64, u64, u64)>, BlobError> {
+        let b_end = block + self.block_size;
+        let mut pos = block + LINK_SIZE;
+        let mut res = Vec::new();
+        while pos < b_end {
+            let (klen, len) = self.section_at(pos, b_end)?;
+            res.push((pos, klen, len));
+            pos += len;
+        }
+        Ok(res)
+    }
+
+    // Reads the lengths of the section at pos and checks it ends inside the block,
+    // so a garbage length is caught here rather than read as a huge blob.
+    // Gives back klen (0 for free) and the length of the whole section.
+    fn section_at(&self, pos: u64, b_end: u64) -> Result<(u64, u64), BlobError> {
+        let klen = self.u64_at(pos)?;
+        let mut vlen = self.u64_at(pos + 8)?;
+        if klen > 0 {
+            vlen &= !LOGGED;
+        }
+        let extra = if klen == 0 { 16 } else { RECORD_EXTRA };
+        match klen.checked_add(vlen).and_then(|n| n.checked_add(extra)) {
+            Some(len) if len <= b_end - pos => Ok((klen, len)),
+            _ => Err(corrupt(pos, "section runs past the end of its block")),
+        }
+    }
+
+    /// All the blobs in one bucket, free sections skipped
+    pub(crate) fn bucket_blobs(&self, bucket: u64) -> Result<Vec<Blob>, BlobError> {
+        let mut res = Vec::new();
+        self.for_each_in_bucket(bucket, |_, b| res.push(b))?;
+        Ok(res)
+    }
+
+    /// Walks the records of one bucket, block by block along its chain,
+    /// calling f with where each one starts and the blob. Free sections are skipped.
+    pub fn for_each_in_bucket<F>(&self, bucket: u64, mut f: F) -> Result<(), BlobError>
+    where
+        F: FnMut(u64, Blob),
+    {
+        for (pos, b) in self.raw_bucket(bucket)? {
+            f(pos, self.resolve(b)?);
+        }
+        Ok(())
+    }
+
+    // the records of a bucket as they are, values in the log left there
+    fn raw_bucket(&self, bucket: u64) -> Result<Vec<(u64, Blob)>, BlobError> {
+        let mut res = Vec::new();
+        for block in self.chain(bucket)? {
+            for (pos, klen, _) in self.sections(block)? {
+                // klen == 0 is a free section
+                if klen > 0 {
+                    res.push((pos, self.blob_at(pos)?));
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// The records of a bucket as they are, values in the log left there
+    pub(crate) fn raw_blobs(&self, bucket: u64) -> Result<Vec<Blob>, BlobError> {
+        Ok(self
+            .raw_bucket(bucket)?
+            .into_iter()
+            .map(|(_, b)| b)
+            .collect())
+    }
+
+    pub fn bucket_stats(&self, bucket: u64) -> Result<BucketStats, BlobError> {
+        let chain = self.chain(bucket)?;
+        let mut st = BucketStats {
+            blocks: chain.len() as u64,
+            live: 0,
+            used_bytes: 0,
+            free_bytes: 0,
+            free_sections: 0,
+            largest_free: 0,
+        };
+        for block in chain {
+            for (_, klen, len) in self.sections(block)? {
+                if klen > 0 {
+                    st.live += 1;
+                    st.used_bytes += len;
+                } else {
+                    st.free_sections += 1;
+                    st.free_bytes += len;
+                    st.largest_free = st.largest_free.max(len);
+                }
+            }
+        }
+        Ok(st)
+    }
+
+    /// Slides the records in a bucket to the front of its blocks, filling
+    /// them in chain order, so each block has at most one free section at its end
+    pub fn compact_bucket(&mut self, bucket: u64) -> Result<(), BlobError> {
+        self.atomic(|s| {
+            let blocks = s.chain(bucket)?.len();
+            match s.pack(&s.raw_blobs(bucket)?, blocks) {
+                Ok(bufs) => s.write_packed(bucket, bufs),
+                // packed like this they do not fit, leave it as it is
+                Err(BlobError::NoRoom) => Ok(()),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    // Lays the blobs out in order, filling one block before the next.
+    // Each buf is what goes after one block's link, NoRoom if it takes more than max.
+    fn pack(&self, blobs: &[Blob], max: usize) -> Result<Vec<Vec<u8>>, BlobError> {
+        let room = self.block_size - LINK_SIZE;
+        let mut bufs: Vec<Vec<u8>> = vec![Vec::new()];
+        for b in blobs {
+            loop {
+                let buf = bufs.last_mut().unwrap();
+                let left = room - buf.len() as u64;
+                if b.record_len() == left || b.record_len() + 16 <= left {
+                    b.out(buf)?;
+                    break;
+                }
+                if bufs.len() >= max {
+                    return Err(BlobError::NoRoom);
+                }
+                bufs.push(Vec::new());
+            }
+        }
+        Ok(bufs)
+    }
+
+    // Writes what pack made over the bucket's blocks, one write per block,
+    // adding overflow blocks if there are more bufs than blocks
+    fn write_packed(&mut self, bucket: u64, mut bufs: Vec<Vec<u8>>) -> Result<(), BlobError> {
+        let room = self.block_size - LINK_SIZE;
+        let mut chain = self.chain(bucket)?;
+        while chain.len() < bufs.len() {
+            let block = self.add_overflow(chain[chain.len() - 1])?;
+            chain.push(block);
+        }
+        bufs.resize(chain.len(), Vec::new());
+        for (block, mut buf) in chain.into_iter().zip(bufs) {
+            // free space is only ever in sections of 16 or more, so what is left is too
+            let left = room - buf.len() as u64;
+            if left > 0 {
+                write_u64(&mut buf, 0)?;
+                write_u64(&mut buf, left - 16)?;
+            }
+            self.write(block + LINK_SIZE, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Does everything in the batch, a bucket at a time, then syncs once.
+    /// Every bucket's new blocks are worked out before any is written,
+    /// so TooBig or NoRoom leave the store as it was, journal or not.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<(), BlobError> {
+        // WriteBatch encodes with bincode
+        self.check_bincode()?;
+        let ops = batch.into_ops();
+        // as each will be in its bucket, before anything goes in the log
+        for op in &ops {
+            if let BatchOp::Insert(b) = op {
+                check_key(b)?;
+                match self.goes_to_log(b) {
+                    true => self.check_size(&b.pointing_to(0))?,
+                    false => self.check_size(b)?,
+                }
+            }
+        }
+        // the planning is part of the change too, so values logged
+        // for a batch that then fails are counted as dead
+        self.atomic(|s| s.apply_batch(ops))?;
+        // clearing the journal syncs, and EveryOp did at the end of atomic
+        if self.sync == SyncPolicy::EveryBatch && self.journal.is_none() {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn apply_batch(&mut self, ops: Vec<BatchOp>) -> Result<(), BlobError> {
+        let mut by_bucket: BTreeMap<u64, Vec<BatchOp>> = BTreeMap::new();
+        for op in ops {
+            let op = match op {
+                BatchOp::Insert(b) => BatchOp::Insert(self.log_big(&b)?.unwrap_or(b)),
+                op => op,
+            };
+            by_bucket
+                .entry(self.bucket_of(op.blob()))
+                .or_default()
+                .push(op);
+        }
+        let mut plan = Vec::new();
+        let mut added = 0;
+        let mut dead = Vec::new();
+        for (bucket, ops) in by_bucket {
+            let mut blobs = self.raw_blobs(bucket)?;
+            for op in ops {
+                let at = blobs.iter().position(|b| b.key_match(op.blob()));
+                match (op, at) {
+                    (BatchOp::Insert(b), Some(i)) => dead.push(std::mem::replace(&mut blobs[i], b)),
+                    (BatchOp::Insert(b), None) => {
+                        blobs.push(b);
+                        added += 1;
+                    }
+                    (BatchOp::Remove(_), Some(i)) => {
+                        dead.push(blobs.remove(i));
+                        added -= 1;
+                    }
+                    (BatchOp::Remove(_), None) => {}
+                }
+            }
+            let max = self.chain(bucket)?.len().max(1 + self.max_chain as usize);
+            plan.push((bucket, self.pack(&blobs, max)?));
+        }
+        // every bucket is worked out before any is written
+        for (bucket, bufs) in plan {
+            self.write_packed(bucket, bufs)?;
+        }
+        for b in &dead {
+            self.dead_value(b)?;
+        }
+        self.inc_elems(added)
+    }
+
+    /// Compacts every bucket that has a block with its free space in more than one piece
+    pub fn compact_all(&mut self) -> Result<(), BlobError> {
+        for bucket in 0..self.nblocks {
+            let st = self.bucket_stats(bucket)?;
+            if st.free_sections > st.blocks {
+                self.compact_bucket(bucket)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every blob in the store, a bucket at a time
+    pub fn iter_blobs(&self) -> BlobIter<'_> {
+        BlobIter::new(self)
+    }
+
+    /// Every pair in the store decoded, in no particular order
+    pub fn iter<K, V>(&self) -> impl Iterator<Item = Result<(K, V), BlobError>> + '_
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        self.bincode_blobs()
+            .map(|r| r.and_then(|b| Ok((b.get_k()?, b.get_v()?))))
+    }
+
+    pub fn keys<K: DeserializeOwned>(&self) -> impl Iterator<Item = Result<K, BlobError>> + '_ {
+        self.bincode_blobs().map(|r| r.and_then(|b| b.get_k()))
+    }
+
+    // every blob, or only CodecMismatch if they are not bincode to decode
+    fn bincode_blobs(&self) -> impl Iterator<Item = Result<Blob, BlobError>> + '_ {
+        let mismatch = self.check_bincode().err();
+        let n = if mismatch.is_some() { 0 } else { usize::MAX };
+        mismatch
+            .map(Err)
+            .into_iter()
+            .chain(self.iter_blobs().take(n))
+    }
+
+    /// Marks every block of the bucket as one empty section again,
+    /// the overflow blocks stay linked for the bucket to use again
+    pub(crate) fn clear_bucket(&mut self, bucket: u64) -> Result<(), BlobError> {
+        self.atomic(|s| {
+            // the records have been moved, pointers into the log and all,
+            // so none of their values are dead
+            let blobs = s.raw_blobs(bucket)?;
+            s.inc_elems(-(blobs.len() as i32))?;
+            for block in s.chain(bucket)? {
+                s.write_free(block + LINK_SIZE, s.block_size - LINK_SIZE - 16)?;
+            }
+            Ok(())
+        })
+    }
+
+    // where the blob with the same key as s_blob is, and the blob itself
+    fn find(&self, s_blob: &Blob) -> Result<Option<(u64, Blob)>, BlobError> {
+        let bucket = self.bucket_of(s_blob);
+        for block in self.chain(bucket)? {
+            for (pos, klen, _) in self.sections(block)? {
+                if klen > 0 {
+                    let b = self.blob_at(pos)?;
+                    if b.key_match(s_blob) {
+                        return Ok(Some((pos, b)));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // marks the l long record at pos as free
+    fn free_at(&mut self, pos: u64, l: u64) -> Result<(), BlobError> {
+        let b_end = self.block_end(pos);
+        //check if next block is empty, then we can join them
+        // l already counts this one's 16, so only the data of the next is added
+        if pos + l < b_end && self.u64_at(pos + l)? == 0 {
+            let nlen = self.u64_at(pos + l + 8)?;
+            return self.write_free(pos, l + nlen);
+        }
+        self.write_free(pos, l - 16)
+    }
+
+    pub fn get<K: Serialize>(&self, k: &K) -> Result<Blob, BlobError> {
+        let s_blob = self.blob_from(k, &0)?;
+        self.get_blob(&s_blob)
+    }
+
+    /// The value for k straight out of the map, no copy made.
+    /// Maps the file first if it is not already.
+    pub fn get_ref<K: Serialize>(&mut self, k: &K) -> Result<&[u8], BlobError> {
+        let s_blob = self.blob_from(k, &0)?;
+        if self.map.is_none() {
+            self.remap()?;
+        }
+        // only where it is can come out of the loop, the slice is made after
+        let (start, end, logged) = self.find_mapped(&s_blob)?.ok_or(BlobError::NotFound)?;
+        let m = self.map.as_ref().unwrap();
+        let v = &m[start as usize..end as usize];
+        if !logged {
+            return Ok(v);
+        }
+        // the record only says where in the value log, which has a map of its own
+        let pos = u64::from_le_bytes(v[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(v[8..].try_into().unwrap());
+        match &mut self.vlog {
+            Some(log) => log.slice(pos, len),
+            None => Err(corrupt(
+                start,
+                "record points into a value log there is none of",
+            )),
+        }
+    }
+
+    // where the value of the record with s_blob's key is in the map,
+    // and whether that is only a pointer into the value log
+    fn find_mapped(&self, s_blob: &Blob) -> Result<Option<(u64, u64, bool)>, BlobError> {
+        let want = s_blob.k_bytes();
+        let bucket = self.bucket_of(s_blob);
+        for block in self.chain(bucket)? {
+            for (pos, klen, len) in self.sections(block)? {
+                // klen 0 is a free section
+                if klen == 0 || klen != want.len() as u64 {
+                    continue;
+                }
+                // sections checked the lengths fit in the block
+                let m = &self.map.as_ref().unwrap()[pos as usize..(pos + len) as usize];
+                let (k, rest) = m[16..].split_at(klen as usize);
+                if k != want {
+                    continue;
+                }
+                let (v, crc) = rest.split_at(rest.len() - 4);
+                let logged = u64::from_le_bytes(m[8..16].try_into().unwrap()) & LOGGED != 0;
+                if u32::from_le_bytes(crc.try_into().unwrap()) != record_crc(k, v, logged) {
+                    return Err(corrupt(pos, "record checksum does not match"));
+                }
+                if logged && v.len() != 16 {
+                    return Err(corrupt(pos, "value log pointer is the wrong length"));
+                }
+                let start = pos + 16 + klen;
+                return Ok(Some((start, start + v.len() as u64, logged)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The blob with the same key as s_blob
+    pub(crate) fn get_blob(&self, s_blob: &Blob) -> Result<Blob, BlobError> {
+        match self.find(s_blob)? {
+            Some((_, b)) => self.resolve(b),
+            None => Err(BlobError::NotFound),
+        }
+    }
+
+    // only looks, the value is not read from the log
+    pub(crate) fn has_blob(&self, s_blob: &Blob) -> Result<bool, BlobError> {
+        Ok(self.find(s_blob)?.is_some())
+    }
+
+    /// Puts the pair in, replacing the value if the key is already there.
+    /// Returns the blob that was replaced.
+    pub fn insert<K: Serialize, V: Serialize>(
+        &mut self,
+        k: K,
+        v: V,
+    ) -> Result<Option<Blob>, BlobError> {
+        let blob = self.blob_from(&k, &v)?;
+        self.replace_blob(&blob)
+    }
+
+    pub(crate) fn replace_blob(&mut self, blob: &Blob) -> Result<Option<Blob>, BlobError> {
+        check_key(blob)?;
+        self.atomic(|s| {
+            let logged = s.log_big(blob)?;
+            s.replace(logged.as_ref().unwrap_or(blob))
+        })
+    }
+
+    fn replace(&mut self, blob: &Blob) -> Result<Option<Blob>, BlobError> {
+        self.check_size(blob)?;
+        let (pos, old) = match self.find(blob)? {
+            Some(found) => found,
+            None => return self.insert_blob(blob).map(|_| None),
+        };
+        let l = old.record_len();
+        // fits where the old one was, the rest of it (if any) becomes free
+        if blob.record_len() == l || blob.record_len() + 16 <= l {
+            self.write_blob(pos, blob)?;
+            if blob.record_len() < l {
+                self.write_free(pos + blob.record_len(), l - blob.record_len() - 16)?;
+            }
+            return self.hand_back(old);
+        }
+        self.free_at(pos, l)?;
+        if let Err(e) = self.place(blob) {
+            // put the old one back so a failed insert changes nothing,
+            // its own slot is free again so there is always room for it
+            self.place(&old)?;
+            return Err(e);
+        }
+        self.hand_back(old)
+    }
+
+    // The old value goes back to the caller, read out of the value log
+    // now, as a later gc may drop it from there
+    fn hand_back(&mut self, old: Blob) -> Result<Option<Blob>, BlobError> {
+        self.dead_value(&old)?;
+        Ok(Some(self.resolve(old)?))
+    }
+
+    /// Only puts the pair in if the key is not there yet, true if it was put in
+    pub fn insert_if_absent<K: Serialize, V: Serialize>(
+        &mut self,
+        k: K,
+        v: V,
+    ) -> Result<bool, BlobError> {
+        let blob = self.blob_from(&k, &v)?;
+        if self.find(&blob)?.is_some() {
+            return Ok(false);
+        }
+        self.insert_blob(&blob)?;
+        Ok(true)
+    }
+
+    /// Gets the blob for k, if it is not there the value from f is put in first
+    pub fn get_or_insert_with<K, V, F>(&mut self, k: K, f: F) -> Result<Blob, BlobError>
+    where
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce() -> V,
+    {
+        let s_blob = self.blob_from(&k, &0)?;
+        if let Some((_, b)) = self.find(&s_blob)? {
+            return self.resolve(b);
+        }
+        let blob = self.blob_from(&k, &f())?;
+        self.insert_blob(&blob)?;
+        Ok(blob)
+    }
+
+    pub fn remove<K: Serialize>(&mut self, k: &K) -> Result<(), BlobError> {
+        let s_blob = self.blob_from(k, &0)?;
+        self.remove_blob(&s_blob).map(|_| ())
+    }
+
+    /// Takes out the blob with the same key as s_blob
+    pub(crate) fn remove_blob(&mut self, s_blob: &Blob) -> Result<Option<Blob>, BlobError> {
+        self.atomic(|s| match s.find(s_blob)? {
+            Some((pos, b)) => {
+                s.free_at(pos, b.record_len())?;
+                s.inc_elems(-1)?;
+                s.hand_back(b)
+            }
+            None => Ok(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_create_file() {
+        let fs = "test_data/create_file";
+        std::fs::remove_file(fs).ok();
+        let bs = BlobStore::new(fs, 1000, 10).unwrap();
+        let blocksize = bs.block_size;
+        // only one handle at a time may have it to write
+        assert!(matches!(BlobStore::open(fs), Err(BlobError::Locked)));
+        drop(bs);
+        let mut b2 = BlobStore::open(fs).unwrap();
+        assert_eq!(b2.block_size, blocksize);
+
+        b2.insert_only("fish", "so long and thanks for all teh fish")
+            .unwrap();
+        drop(b2);
+
+        let mut bs = BlobStore::open(fs).unwrap();
+        // the name is taken, so it is not made over
+        assert!(matches!(BlobStore::new(fs, 1000, 10), Err(BlobError::IO(_))));
+
+        bs.insert_only(55, "hello people").unwrap();
+        bs.insert_only("green", "Another really long data thing")
+            .unwrap();
+        bs.insert_only("sandwich", vec!["young", "old", "not_sure"])
+            .unwrap();
+
+        //let blob = bs.get(&55).unwrap();
+        assert_eq!(
+            bs.get(&55).unwrap().get_v::<String>().unwrap(),
+            "hello people".to_string()
+        );
+        assert_eq!(
+            bs.get(&"green").unwrap().get_v::<String>().unwrap(),
+            "Another really long data thing".to_string()
+        );
+        let vback: Vec<String> = bs.get(&"sandwich").unwrap().get_v().unwrap();
+        assert_eq!(
+            vback,
+            vec![
+                "young".to_string(),
+                "old".to_string(),
+                "not_sure".to_string()
+            ]
+        );
+
+        bs.remove(&"green").unwrap();
+
+        assert!(bs.get(&"green").is_err());
+        assert!(bs.get(&55).is_ok());
+    }
+
+    #[test]
+    pub fn test_create_race() {
+        let fs = "test_data/bs_create_race";
+        std::fs::remove_file(fs).ok();
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            // an open that lands while the file is being made must never see half of it
+            s.spawn(|| {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    match BlobStore::open(fs) {
+                        Ok(_) | Err(BlobError::Locked) | Err(BlobError::IO(_)) => {}
+                        Err(e) => panic!("open saw a half made file: {}", e),
+                    }
+                }
+            });
+            for _ in 0..200 {
+                std::fs::remove_file(fs).ok();
+                BlobStore::new(fs, 100, 50).unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        // nothing left behind under the temp names
+        let left = std::fs::read_dir("test_data").unwrap();
+        assert!(!left.into_iter().any(|e| {
+            let name = e.unwrap().file_name().into_string().unwrap();
+            name.starts_with("bs_create_race.") && name.ends_with(".new")
+        }));
+    }
+
+    #[test]
+    pub fn test_reread() {
+        std::fs::remove_file("test_data/bs_reread").ok();
+        let bs = BlobStore::new("test_data/bs_reread", 1000, 10).unwrap();
+        drop(bs);
+        let b2 = BlobStore::open("test_data/bs_reread").unwrap();
+        assert_eq!(b2.block_size, 1000);
+    }
+
+    // every blob in the store, none may share a key
+    fn assert_no_dups(bs: &mut BlobStore) -> usize {
+        let mut all = Vec::new();
+        for bucket in 0..bs.nblocks {
+            all.extend(bs.bucket_blobs(bucket).unwrap());
+        }
+        for (i, a) in all.iter().enumerate() {
+            for b in &all[i + 1..] {
+                assert!(!a.key_match(b), "key in the store twice");
+            }
+        }
+        all.len()
+    }
+
+    #[test]
+    pub fn test_insert_replaces() {
+        let fs = "test_data/bs_insert_replace";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 4).unwrap();
+        assert!(bs.insert("a", "first").unwrap().is_none());
+        // same length, shorter (leaves a hole), longer (has to move)
+        for v in ["again", "sh", "a much longer value than before"] {
+            let old = bs.insert("a", v).unwrap().unwrap();
+            assert_ne!(old.get_v::<String>().unwrap(), v);
+            assert_eq!(bs.get(&"a").unwrap().get_v::<String>().unwrap(), v);
+        }
+        for i in 0..10 {
+            bs.insert(i, i).unwrap();
+            bs.insert(i, i * 100).unwrap();
+        }
+        assert_eq!(assert_no_dups(&mut bs), 11);
+        for i in 0..10 {
+            assert_eq!(bs.get(&i).unwrap().get_v::<i32>().unwrap(), i * 100);
+        }
+    }
+
+    #[test]
+    pub fn test_failed_replace_keeps_old() {
+        let fs = "test_data/bs_failed_replace";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 100, 1).unwrap();
+        bs.set_max_chain(0);
+        bs.insert(1, "small").unwrap();
+        bs.insert(2, "rest").unwrap();
+        let big = "x".repeat(40);
+        assert!(matches!(bs.insert(1, &big), Err(BlobError::NoRoom)));
+        assert_eq!(bs.get(&1).unwrap().get_v::<String>().unwrap(), "small");
+        assert_eq!(assert_no_dups(&mut bs), 2);
+    }
+
+    #[test]
+    pub fn test_insert_if_absent() {
+        let fs = "test_data/bs_if_absent";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 4).unwrap();
+        assert!(bs.insert_if_absent("k", 1).unwrap());
+        assert!(!bs.insert_if_absent("k", 2).unwrap());
+        assert_eq!(bs.get(&"k").unwrap().get_v::<i32>().unwrap(), 1);
+
+        let b = bs.get_or_insert_with("j", || 5).unwrap();
+        assert_eq!(b.get_v::<i32>().unwrap(), 5);
+        let b = bs.get_or_insert_with("j", || -> i32 { panic!("already there") });
+        assert_eq!(b.unwrap().get_v::<i32>().unwrap(), 5);
+        assert_eq!(assert_no_dups(&mut bs), 2);
+    }
+
+    #[test]
+    pub fn test_raw() {
+        let fs = "test_data/bs_raw";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 4).unwrap();
+        let k = bincode::serialize(&1).unwrap();
+        let v = bincode::serialize("one").unwrap();
+        assert!(bs.insert_raw(&k, &v, Bincode::ID).unwrap().is_none());
+        assert_eq!(bs.get(&1).unwrap().get_v::<String>().unwrap(), "one");
+        assert_eq!(bs.get_raw(&k, Bincode::ID).unwrap().v_bytes(), &v[..]);
+        // bytes said to be json do not go in a bincode file
+        let json = crate::codec::Json::ID;
+        let mismatch = |r| matches!(r, Err(BlobError::CodecMismatch { found: 1, .. }));
+        assert!(mismatch(bs.get_raw(&k, json).map(|_| None)));
+        assert!(mismatch(bs.insert_raw(&k, b"2", json)));
+        assert!(mismatch(bs.remove_raw(&k, json)));
+        assert!(bs.remove_raw(&k, Bincode::ID).unwrap().is_some());
+        assert!(bs.remove_raw(&k, Bincode::ID).unwrap().is_none());
+    }
+
+    #[test]
+    pub fn test_count() {
+        let fs = "test_data/bs_count";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 400, 4).unwrap();
+        assert!(bs.is_empty());
+        assert_eq!(bs.capacity(), 4 * (400 - LINK_SIZE));
+        for i in 0..20 {
+            bs.insert(i, i).unwrap();
+        }
+        // replacing and missing keys change nothing
+        bs.insert(3, "three").unwrap();
+        assert!(!bs.insert_if_absent(4, 4).unwrap());
+        bs.get_or_insert_with(5, || 5).unwrap();
+        bs.remove(&100).unwrap();
+        assert_eq!(bs.len(), 20);
+
+        bs.remove(&3).unwrap();
+        bs.remove(&3).unwrap();
+        bs.insert_only(50, 50).unwrap();
+        assert_eq!(bs.len(), 20);
+        assert_eq!(bs.verify_count().unwrap(), 20);
+        drop(bs);
+
+        let b2 = BlobStore::open(fs).unwrap();
+        assert_eq!(b2.len(), 20);
+        assert_eq!(b2.verify_count().unwrap(), 20);
+    }
+
+    // what every key from 0 to 10 holds, and checks the count is right
+    fn contents(bs: &mut BlobStore) -> Vec<Option<String>> {
+        assert_eq!(bs.verify_count().unwrap(), bs.len());
+        (0..10)
+            .map(|i| bs.get(&i).ok().map(|b| b.get_v().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_crash_at_every_byte() {
+        let base = "test_data/bs_crash_base";
+        let fs = "test_data/bs_crash";
+        std::fs::remove_file(base).ok();
+        let mut bs = BlobStore::new(base, 500, 2).unwrap();
+        for i in 0..6 {
+            bs.insert(i, format!("value {}", i)).unwrap();
+        }
+        let before = contents(&mut bs);
+        drop(bs);
+
+        type Op = fn(&mut BlobStore) -> Result<(), BlobError>;
+        let ops: Vec<Op> = vec![
+            |bs| bs.insert(8, "a new one").map(|_| ()),
+            |bs| bs.insert(2, "longer than it was before").map(|_| ()),
+            |bs| bs.insert(3, "v").map(|_| ()),
+            |bs| bs.remove(&4),
+            // too big for what is left, so it takes an overflow block
+            |bs| bs.insert(9, "x".repeat(300)).map(|_| ()),
+        ];
+        for op in ops {
+            let start = |budget| {
+                std::fs::remove_file(journal_name(fs)).ok();
+                std::fs::copy(base, fs).unwrap();
+                let mut bs = BlobStore::open(fs).unwrap().with_journal().unwrap();
+                bs.fail_after = Some(budget);
+                bs
+            };
+            // how many bytes the whole change writes, journal included
+            let mut bs = start(u64::MAX);
+            op(&mut bs).unwrap();
+            let total = u64::MAX - bs.fail_after.unwrap();
+            let after = contents(&mut bs);
+            assert_ne!(before, after);
+            drop(bs);
+
+            for n in 0..total {
+                let mut bs = start(n);
+                assert!(op(&mut bs).is_err());
+                drop(bs);
+                // never finished, so opening must give back just what was there
+                let mut bs = BlobStore::open(fs).unwrap();
+                assert_eq!(contents(&mut bs), before, "crash after {} bytes", n);
+                assert_eq!(bs.flen, std::fs::metadata(base).unwrap().len());
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_rollback_fails_too() {
+        let fs = "test_data/bs_rollback_fails";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 500, 2).unwrap().with_journal().unwrap();
+        bs.insert(1, "one").unwrap();
+        // once the budget is gone every write fails, the undo's as well,
+        // so a budget that lasts past the journal but not the store's own
+        // write leaves both the change and its rollback failed
+        let mut n = 0;
+        let (error, rollback) = loop {
+            bs.fail_after = Some(n);
+            match bs.insert(1, "a longer one") {
+                Err(BlobError::RollbackFailed { error, rollback }) => break (error, rollback),
+                Err(BlobError::IO(_)) => n += 1,
+                r => panic!("budget {} gave {:?}", n, r.map(|_| ())),
+            }
+        };
+        assert!(matches!(*error, BlobError::IO(_)));
+        assert!(matches!(*rollback, BlobError::IO(_)));
+        drop(bs);
+        // the journal still has the change, so open puts it back
+        let bs = BlobStore::open(fs).unwrap();
+        assert_eq!(bs.get(&1).unwrap().get_v::<String>().unwrap(), "one");
+    }
+
+    fn poke(fname: &str, pos: u64, data: &[u8]) {
+        let mut f = OpenOptions::new().write(true).open(fname).unwrap();
+        f.seek(SeekFrom::Start(pos)).unwrap();
+        f.write_all(data).unwrap();
+    }
+
+    fn corrupt_at<T>(r: Result<T, BlobError>) -> u64 {
+        match r {
+            Err(BlobError::Corrupt { offset, .. }) => offset,
+            Err(e) => panic!("expected Corrupt, got {}", e),
+            Ok(_) => panic!("expected Corrupt, got Ok"),
+        }
+    }
+
+    #[test]
+    pub fn test_bad_header() {
+        let fs = "test_data/bs_bad_header";
+        std::fs::remove_file(fs).ok();
+        std::fs::write(fs, "not a blob file at all, just some text in a file").unwrap();
+        assert_eq!(corrupt_at(BlobStore::open(fs)), 0);
+
+        std::fs::remove_file(fs).ok();
+        BlobStore::new(fs, 100, 2).unwrap();
+        poke(fs, 8, &99u64.to_le_bytes());
+        assert_eq!(corrupt_at(BlobStore::open(fs)), 8);
+
+        poke(fs, 8, &1u64.to_le_bytes());
+        assert_eq!(&std::fs::read(fs).unwrap()[..16], b"BLOBFILE\x01\0\0\0\0\0\0\0");
+        poke(fs, 32, &3u64.to_le_bytes()); // nblocks
+        assert_eq!(corrupt_at(BlobStore::open(fs)), 24);
+    }
+
+    #[test]
+    pub fn test_pre_series_file() {
+        // laid out as the code before the magic wrote it: hseed, block_size,
+        // nblocks, elems, then blocks of klen, vlen, k, v with no link or crc.
+        // Its keys were put in buckets by the old hmap::hash, so it must be
+        // turned away rather than looked up with key_hash.
+        let fs = "test_data/bs_pre_series";
+        std::fs::remove_file(fs).ok();
+        let k = bincode::serialize("fish").unwrap();
+        let v = bincode::serialize("so long").unwrap();
+        let mut d = Vec::new();
+        for n in [55u64, 100, 2, 1] {
+            d.extend_from_slice(&n.to_le_bytes());
+        }
+        d.extend_from_slice(&(k.len() as u64).to_le_bytes());
+        d.extend_from_slice(&(v.len() as u64).to_le_bytes());
+        d.extend_from_slice(&k);
+        d.extend_from_slice(&v);
+        let used = 16 + (k.len() + v.len()) as u64;
+        d.extend_from_slice(&0u64.to_le_bytes());
+        d.extend_from_slice(&(100 - used - 16).to_le_bytes());
+        d.resize(32 + 100, 0);
+        d.extend_from_slice(&0u64.to_le_bytes());
+        d.extend_from_slice(&84u64.to_le_bytes());
+        d.resize(32 + 200, 0);
+        std::fs::write(fs, &d).unwrap();
+
+        assert_eq!(corrupt_at(BlobStore::open(fs)), 0);
+        assert_eq!(corrupt_at(BlobStore::open_read_only(fs)), 0);
+        // and nothing was written to it on the way
+        assert_eq!(std::fs::read(fs).unwrap(), d);
+    }
+
+    #[test]
+    pub fn test_bad_records() {
+        let fs = "test_data/bs_bad_records";
+        std::fs::remove_file(fs).ok();
+        // one block, so the record is right after the header and link
+        let rec = COUNT_SIZE + LINK_SIZE;
+        let mut bs = BlobStore::new(fs, 200, 1).unwrap();
+        bs.insert(1, "some value").unwrap();
+        drop(bs);
+
+        // one bit flipped in the value
+        poke(fs, rec + 16 + 4 + 9, b"X");
+        let bs = BlobStore::open(fs).unwrap();
+        assert_eq!(corrupt_at(bs.get(&1)), rec);
+        assert_eq!(corrupt_at(bs.verify_count()), rec);
+        drop(bs);
+
+        // a key length that would have meant allocating exabytes
+        poke(fs, rec, &(u64::MAX - 3).to_le_bytes());
+        let mut bs = BlobStore::open(fs).unwrap();
+        assert_eq!(corrupt_at(bs.get(&1)), rec);
+        assert_eq!(corrupt_at(bs.insert(1, 2)), rec);
+        drop(bs);
+
+        // a link pointing back into the buckets' own blocks
+        poke(fs, COUNT_SIZE, &COUNT_SIZE.to_le_bytes());
+        let bs = BlobStore::open(fs).unwrap();
+        assert_eq!(corrupt_at(bs.get(&1)), COUNT_SIZE);
+    }
+
+    #[test]
+    pub fn test_iter() {
+        let fs = "test_data/bs_iter";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 400, 5).unwrap();
+        assert_eq!(bs.iter_blobs().count(), 0);
+        for i in 0..20 {
+            bs.insert(i, format!("v{}", i)).unwrap();
+        }
+        // leave some holes to be skipped
+        for i in (0..20).step_by(3) {
+            bs.remove(&i).unwrap();
+        }
+        let mut want: Vec<(i32, String)> = (0..20)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (i, format!("v{}", i)))
+            .collect();
+        let mut got: Vec<(i32, String)> = bs.iter().map(|r| r.unwrap()).collect();
+        got.sort();
+        assert_eq!(got, want);
+
+        let mut keys: Vec<i32> = bs.keys().map(|r| r.unwrap()).collect();
+        keys.sort();
+        assert_eq!(keys, want.iter().map(|p| p.0).collect::<Vec<_>>());
+
+        // iterating only reads, so gets can go on at the same time
+        for k in bs.keys::<i32>() {
+            let k = k.unwrap();
+            assert_eq!(bs.get(&k).unwrap().get_v::<String>().unwrap(), format!("v{}", k));
+        }
+
+        // each bucket walks in file order, and together they cover everything
+        let mut seen = Vec::new();
+        for bucket in 0..bs.nblocks() {
+            let mut last = 0;
+            bs.for_each_in_bucket(bucket, |pos, b| {
+                assert!(pos > last);
+                last = pos;
+                seen.push((b.get_k().unwrap(), b.get_v().unwrap()));
+            })
+            .unwrap();
+        }
+        seen.sort();
+        want.sort();
+        assert_eq!(seen, want);
+    }
+
+    #[test]
+    pub fn test_iter_stops_on_corrupt() {
+        let fs = "test_data/bs_iter_corrupt";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 1).unwrap();
+        bs.insert(1, 1).unwrap();
+        drop(bs);
+        poke(fs, COUNT_SIZE + LINK_SIZE + 16 + 4, b"X");
+        let bs = BlobStore::open(fs).unwrap();
+        let res: Vec<_> = bs.iter_blobs().collect();
+        assert_eq!(res.len(), 1);
+        assert!(matches!(res[0], Err(BlobError::Corrupt { .. })));
+    }
+
+    #[test]
+    pub fn test_compact() {
+        let fs = "test_data/bs_compact";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 500, 1).unwrap();
+        for i in 0..10 {
+            bs.insert(i, "0123456789").unwrap();
+        }
+        // every other one out, nothing next to another hole to join with
+        for i in (0..10).step_by(2) {
+            bs.remove(&i).unwrap();
+        }
+        let st = bs.bucket_stats(0).unwrap();
+        assert_eq!(st.live, 5);
+        assert_eq!(st.free_sections, 6);
+        assert_eq!(st.used_bytes + st.free_bytes, 500 - LINK_SIZE);
+        assert!(st.fragmentation() > 0.5);
+
+        bs.compact_all().unwrap();
+        let st2 = bs.bucket_stats(0).unwrap();
+        assert_eq!(st2.free_sections, 1);
+        assert_eq!(st2.free_bytes, st.free_bytes);
+        assert_eq!(st2.fragmentation(), 0.0);
+        for i in (1..10).step_by(2) {
+            assert_eq!(bs.get(&i).unwrap().get_v::<String>().unwrap(), "0123456789");
+        }
+        assert_eq!(bs.verify_count().unwrap(), 5);
+    }
+
+    #[test]
+    pub fn test_insert_compacts_when_needed() {
+        let fs = "test_data/bs_auto_compact";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 500, 1).unwrap();
+        for i in 0..10 {
+            bs.insert(i, "0123456789").unwrap();
+        }
+        for i in (0..10).step_by(2) {
+            bs.remove(&i).unwrap();
+        }
+        // no one hole is big enough, all of them together are
+        let big = "x".repeat(100);
+        bs.insert(20, &big).unwrap();
+        assert_eq!(bs.get(&20).unwrap().get_v::<String>().unwrap(), big);
+        assert_eq!(bs.len(), 6);
+    }
+
+    fn fill_up(bs: &mut BlobStore, from: i32) -> i32 {
+        let mut n = from;
+        loop {
+            match bs.insert(n, n) {
+                Ok(_) => n += 1,
+                Err(BlobError::NoRoom) => return n,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_overflow_chain() {
+        let fs = "test_data/bs_overflow";
+        std::fs::remove_file(fs).ok();
+        // how many fit with no overflow at all
+        let one = "test_data/bs_overflow_one";
+        std::fs::remove_file(one).ok();
+        let mut b1 = BlobStore::new(one, 100, 1).unwrap();
+        b1.set_max_chain(0);
+        let per_block = fill_up(&mut b1, 0);
+
+        let mut bs = BlobStore::new(fs, 100, 1).unwrap();
+
+        let n = fill_up(&mut bs, 0);
+        assert_eq!(n, per_block * (1 + MAX_CHAIN as i32));
+        assert_eq!(bs.bucket_stats(0).unwrap().blocks, 1 + MAX_CHAIN);
+        assert_eq!(bs.capacity(), (1 + MAX_CHAIN) * (100 - LINK_SIZE));
+        for i in 0..n {
+            assert_eq!(bs.get(&i).unwrap().get_v::<i32>().unwrap(), i);
+        }
+        assert_eq!(bs.iter_blobs().count() as i32, n);
+
+        // emptied blocks stay in the chain and get used again
+        let flen = std::fs::metadata(fs).unwrap().len();
+        for i in 0..n {
+            bs.remove(&i).unwrap();
+        }
+        assert!(bs.is_empty());
+        assert_eq!(fill_up(&mut bs, 0), n);
+        assert_eq!(std::fs::metadata(fs).unwrap().len(), flen);
+        drop(bs);
+
+        let mut bs = BlobStore::open(fs).unwrap();
+        assert_eq!(bs.verify_count().unwrap(), n as u64);
+        bs.set_max_chain(MAX_CHAIN + 1);
+        assert_eq!(fill_up(&mut bs, n), n + per_block);
+    }
+
+    #[test]
+    pub fn test_mmap() {
+        let fs = "test_data/bs_mmap";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 100, 1).unwrap().with_mmap().unwrap();
+        // overflow blocks grow the file, so the map has to follow it
+        let n = fill_up(&mut bs, 0);
+        assert_eq!(bs.bucket_stats(0).unwrap().blocks, 1 + MAX_CHAIN);
+        for i in 0..n {
+            let want = bincode::serialize(&i).unwrap();
+            assert_eq!(bs.get_ref(&i).unwrap(), &want[..]);
+            assert_eq!(bs.get(&i).unwrap().get_v::<i32>().unwrap(), i);
+        }
+        assert!(matches!(bs.get_ref(&n), Err(BlobError::NotFound)));
+        // writes show through the map
+        bs.insert(3, 33).unwrap();
+        bs.remove(&4).unwrap();
+        assert_eq!(bs.get_ref(&3).unwrap(), &bincode::serialize(&33).unwrap()[..]);
+        assert!(matches!(bs.get_ref(&4), Err(BlobError::NotFound)));
+        drop(bs);
+
+        // get_ref maps on its own when the store is not
+        let mut bs = BlobStore::open(fs).unwrap();
+        assert_eq!(bs.get_ref(&5).unwrap(), &bincode::serialize(&5).unwrap()[..]);
+        let rec = bs.find(&Blob::from(&5, &0).unwrap()).unwrap().unwrap().0;
+        drop(bs);
+        poke(fs, rec + 16 + 4, &[9]);
+        let mut bs = BlobStore::open_read_only(fs).unwrap();
+        assert_eq!(corrupt_at(bs.get_ref(&5)), rec);
+        assert_eq!(bs.get(&3).unwrap().get_v::<i32>().unwrap(), 33);
+        assert_eq!(bs.len(), n as u64 - 1);
+    }
+
+    #[test]
+    pub fn test_read_only() {
+        let fs = "test_data/bs_read_only";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 2).unwrap().with_journal().unwrap();
+        bs.insert(1, "one").unwrap();
+        drop(bs);
+
+        // an empty journal is fine
+        let mut ro = BlobStore::open_read_only(fs).unwrap();
+        assert_eq!(ro.get(&1).unwrap().get_v::<String>().unwrap(), "one");
+        assert!(matches!(ro.insert(2, "two"), Err(BlobError::ReadOnly)));
+        assert!(matches!(ro.remove(&1), Err(BlobError::ReadOnly)));
+        assert!(matches!(ro.compact_bucket(0), Err(BlobError::ReadOnly)));
+        assert_eq!(ro.iter::<i32, String>().count(), 1);
+        drop(ro);
+
+        // a change that never finished has to be undone by a writer first
+        std::fs::write(journal_name(fs), [1u8; 30]).unwrap();
+        assert_eq!(corrupt_at(BlobStore::open_read_only(fs)), 0);
+        drop(BlobStore::open(fs).unwrap());
+        assert!(BlobStore::open_read_only(fs).is_ok());
+    }
+
+    #[test]
+    pub fn test_locking() {
+        let fs = "test_data/bs_locking";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 2).unwrap();
+        bs.insert(1, 10).unwrap();
+        assert!(matches!(BlobStore::open(fs), Err(BlobError::Locked)));
+        assert!(matches!(
+            BlobStore::open_read_only(fs),
+            Err(BlobError::Locked)
+        ));
+        assert!(matches!(
+            BlobStore::new_or_open(fs, 200, 2),
+            Err(BlobError::Locked)
+        ));
+        drop(bs);
+
+        // readers share it, but keep writers out
+        let r1 = BlobStore::open_read_only(fs).unwrap();
+        let r2 = BlobStore::open_read_only(fs).unwrap();
+        assert!(matches!(BlobStore::open(fs), Err(BlobError::Locked)));
+        assert_eq!(r1.get(&1).unwrap().get_v::<i32>().unwrap(), 10);
+        assert_eq!(r2.get(&1).unwrap().get_v::<i32>().unwrap(), 10);
+        drop(r1);
+        assert!(matches!(BlobStore::open(fs), Err(BlobError::Locked)));
+        drop(r2);
+
+        // it goes with the handle
+        let mut bs = BlobStore::open(fs).unwrap();
+        bs.insert(2, 20).unwrap();
+        assert_eq!(bs.len(), 2);
+    }
+
+    #[test]
+    pub fn test_write_batch() {
+        let fs = "test_data/bs_batch";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 400, 4).unwrap();
+        for i in 0..10 {
+            bs.insert(i, i).unwrap();
+        }
+        let mut wb = WriteBatch::new();
+        for i in 5..30 {
+            wb.insert(i, i * 2).unwrap();
+        }
+        for i in (0..30).step_by(3) {
+            wb.remove(&i).unwrap();
+        }
+        // in order, so this one comes back
+        wb.insert(3, 333).unwrap();
+        assert_eq!(wb.len(), 36);
+        bs.write_batch(wb).unwrap();
+
+        for i in 0..30 {
+            let want = match i {
+                3 => 333,
+                _ if i % 3 == 0 => {
+                    assert!(matches!(bs.get(&i), Err(BlobError::NotFound)));
+                    continue;
+                }
+                _ if i < 5 => i,
+                _ => i * 2,
+            };
+            assert_eq!(bs.get(&i).unwrap().get_v::<i32>().unwrap(), want);
+        }
+        assert_eq!(bs.len(), 21);
+        assert_eq!(bs.verify_count().unwrap(), 21);
+        assert_no_dups(&mut bs);
+        // written a block at a time, so no holes left between records
+        for bucket in 0..4 {
+            let st = bs.bucket_stats(bucket).unwrap();
+            assert!(st.free_sections <= st.blocks);
+        }
+    }
+
+    #[test]
+    pub fn test_batch_all_or_nothing() {
+        let fs = "test_data/bs_batch_fail";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 100, 1).unwrap();
+        bs.set_max_chain(0);
+        bs.insert(1, 1).unwrap();
+        let before = std::fs::read(fs).unwrap();
+
+        let mut wb = WriteBatch::new();
+        wb.remove(&1).unwrap();
+        for i in 10..20 {
+            wb.insert(i, i).unwrap();
+        }
+        assert!(matches!(bs.write_batch(wb), Err(BlobError::NoRoom)));
+
+        let mut wb = WriteBatch::new();
+        wb.insert(2, 2).unwrap();
+        wb.insert(3, "x".repeat(100)).unwrap();
+        assert!(matches!(bs.write_batch(wb), Err(BlobError::TooBig(_))));
+
+        assert_eq!(std::fs::read(fs).unwrap(), before);
+        assert_eq!(bs.len(), 1);
+        assert_eq!(bs.get(&1).unwrap().get_v::<i32>().unwrap(), 1);
+    }
+
+    #[test]
+    pub fn test_sync_policy() {
+        let fs = "test_data/bs_sync";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 400, 4).unwrap();
+        let batch = |from: i32| {
+            let mut wb = WriteBatch::new();
+            for i in from..from + 10 {
+                wb.insert(i, i).unwrap();
+            }
+            wb
+        };
+        assert_eq!(bs.sync_policy(), SyncPolicy::EveryBatch);
+        bs.insert(1, 1).unwrap();
+        assert_eq!(bs.syncs, 0);
+        bs.write_batch(batch(10)).unwrap();
+        assert_eq!(bs.syncs, 1);
+
+        bs.set_sync_policy(SyncPolicy::EveryOp);
+        // a new key goes through several nested changes, still one sync
+        bs.insert(2, 2).unwrap();
+        bs.remove(&1).unwrap();
+        assert_eq!(bs.syncs, 3);
+        bs.write_batch(batch(20)).unwrap();
+        assert_eq!(bs.syncs, 4);
+
+        bs.set_sync_policy(SyncPolicy::Never);
+        bs.insert(3, 3).unwrap();
+        bs.write_batch(batch(30)).unwrap();
+        assert_eq!(bs.syncs, 4);
+        bs.sync().unwrap();
+        assert_eq!(bs.syncs, 5);
+        assert_eq!(bs.verify_count().unwrap(), 32);
+    }
+
+    // the store and whatever value logs it has had
+    fn remove_store(fs: &str) {
+        std::fs::remove_file(fs).ok();
+        std::fs::remove_file(journal_name(fs)).ok();
+        for gen in 0..50 {
+            std::fs::remove_file(vlog_name(fs, gen)).ok();
+        }
+    }
+
+    #[test]
+    pub fn test_value_log() {
+        let fs = "test_data/bs_vlog";
+        remove_store(fs);
+        let big = |i: usize| format!("{}", i).repeat(2000 + i);
+        let mut bs = BlobStore::new(fs, 400, 2).unwrap();
+        assert!(matches!(
+            bs.insert(1usize, big(1)),
+            Err(BlobError::TooBig(_))
+        ));
+        let mut bs = bs.with_value_log(50).unwrap();
+        for i in 0..5 {
+            assert!(bs.insert(i, big(i)).unwrap().is_none());
+            bs.insert(i + 100, "small").unwrap();
+        }
+        // only the pointers are in the buckets
+        assert_eq!(bs.total_blocks(), 2);
+        let old = bs.insert(3usize, big(33)).unwrap().unwrap();
+        assert_eq!(old.get_v::<String>().unwrap(), big(3));
+        let mut wb = WriteBatch::new();
+        wb.insert(5usize, big(5)).unwrap();
+        wb.remove(&4usize).unwrap();
+        bs.write_batch(wb).unwrap();
+        let want = |i: usize| match i {
+            3 => Some(big(33)),
+            4 => None,
+            _ => Some(big(i)),
+        };
+        for i in 0..6 {
+            assert_eq!(bs.get(&i).ok().map(|b| b.get_v().unwrap()), want(i));
+        }
+        assert_eq!(bs.iter::<usize, String>().count(), 10);
+        assert_eq!(bs.verify_count().unwrap(), 10);
+        let (len, dead) = bs.value_log_stats().unwrap();
+        assert_eq!(
+            dead,
+            2 * ENTRY_EXTRA + 8 * 2 + big(3).len() as u64 + big(4).len() as u64
+        );
+        assert!(dead < len);
+        drop(bs);
+
+        // open finds the log from the header
+        let mut bs = BlobStore::open(fs).unwrap();
+        let want_ref = bincode::serialize(&big(2)).unwrap();
+        assert_eq!(bs.get_ref(&2usize).unwrap(), &want_ref[..]);
+        assert_eq!(bs.get(&5usize).unwrap().get_v::<String>().unwrap(), big(5));
+        bs.compact_all().unwrap();
+        assert_eq!(bs.get(&0usize).unwrap().get_v::<String>().unwrap(), big(0));
+        drop(bs);
+        let mut ro = BlobStore::open_read_only(fs).unwrap();
+        assert_eq!(ro.get_ref(&2usize).unwrap(), &want_ref[..]);
+
+        // a damaged entry is caught by its own crc
+        let b = ro
+            .find(&Blob::from(&1usize, &0).unwrap())
+            .unwrap()
+            .unwrap()
+            .1;
+        let (pos, _) = b.log_pointer().unwrap();
+        drop(ro);
+        poke(&vlog_name(fs, 0), pos + 20, b"?");
+        let bs = BlobStore::open(fs).unwrap();
+        assert_eq!(corrupt_at(bs.get(&1usize)), pos);
+    }
+
+    #[test]
+    pub fn test_value_log_gc() {
+        let fs = "test_data/bs_vlog_gc";
+        remove_store(fs);
+        let mut bs = BlobStore::new(fs, 200, 2)
+            .unwrap()
+            .with_value_log(100)
+            .unwrap();
+        let entry = ENTRY_EXTRA + bincode::serialize(&"x".repeat(1000)).unwrap().len() as u64;
+        for i in 0..4 {
+            bs.insert(i, "x".repeat(1000)).unwrap();
+        }
+        // going over the same keys, gc'd every time half the log is dead
+        for round in 0..10 {
+            for i in 0..4 {
+                bs.insert(i, format!("{}", round).repeat(1000)).unwrap();
+                if bs.gc_due() {
+                    bs.gc_value_log().unwrap();
+                }
+            }
+            assert!(bs.value_log_stats().unwrap().0 < 8 * entry);
+        }
+        assert!(bs.vlog_gen > 0);
+        assert!(!Path::new(&vlog_name(fs, bs.vlog_gen - 1)).exists());
+        for i in 0..4 {
+            assert_eq!(
+                bs.get(&i).unwrap().get_v::<String>().unwrap(),
+                "9".repeat(1000)
+            );
+        }
+
+        // one removed is not enough for it to be due, gc takes it back anyway
+        bs.remove(&0).unwrap();
+        assert!(!bs.gc_due());
+        bs.gc_value_log().unwrap();
+        assert_eq!(bs.value_log_stats().unwrap(), (3 * entry, 0));
+        let gen = bs.vlog_gen;
+        drop(bs);
+        let bs = BlobStore::open(fs).unwrap();
+        assert_eq!(bs.vlog_gen, gen);
+        assert_eq!(
+            bs.get(&3).unwrap().get_v::<String>().unwrap(),
+            "9".repeat(1000)
+        );
+        assert!(matches!(bs.get(&0), Err(BlobError::NotFound)));
+    }
+
+    #[test]
+    pub fn test_value_log_failed_batch() {
+        let fs = "test_data/bs_vlog_batch";
+        remove_store(fs);
+        let mut bs = BlobStore::new(fs, 100, 1)
+            .unwrap()
+            .with_value_log(20)
+            .unwrap();
+        bs.set_max_chain(0);
+        let entry = ENTRY_EXTRA + bincode::serialize(&"v".repeat(500)).unwrap().len() as u64;
+
+        // too big even as a pointer, found before the other value is logged
+        let mut wb = WriteBatch::new();
+        wb.insert(1, "v".repeat(500)).unwrap();
+        wb.insert("k".repeat(100), 2).unwrap();
+        assert!(matches!(bs.write_batch(wb), Err(BlobError::TooBig(_))));
+        assert_eq!(bs.value_log_stats().unwrap(), (0, 0));
+
+        // no room only shows once it is logged, so the entry is dead
+        let mut wb = WriteBatch::new();
+        wb.insert(1, "v".repeat(500)).unwrap();
+        for i in 10..20 {
+            wb.insert(i, i).unwrap();
+        }
+        assert!(matches!(bs.write_batch(wb), Err(BlobError::NoRoom)));
+        assert_eq!(bs.value_log_stats().unwrap(), (entry, entry));
+        assert!(bs.gc_due());
+        assert_eq!(bs.len(), 0);
+        drop(bs);
+        let mut bs = BlobStore::open(fs).unwrap();
+        assert_eq!(bs.value_log_stats().unwrap(), (entry, entry));
+        bs.gc_value_log().unwrap();
+        assert_eq!(bs.value_log_stats().unwrap(), (0, 0));
+    }
+
+    // a power cut: whatever of the value log was never synced is lost
+    fn lose_unsynced(bs: BlobStore) {
+        let name = vlog_name(&bs.log_base, bs.vlog_gen);
+        let synced = bs.vlog.as_ref().unwrap().synced();
+        drop(bs);
+        let f = OpenOptions::new().write(true).open(name).unwrap();
+        f.set_len(synced).unwrap();
+    }
+
+    #[test]
+    pub fn test_value_log_crash() {
+        let base = "test_data/bs_vlog_crash_base";
+        let fs = "test_data/bs_vlog_crash";
+        remove_store(base);
+        let mut bs = BlobStore::new(base, 200, 2)
+            .unwrap()
+            .with_value_log(20)
+            .unwrap();
+        for i in 0..6 {
+            bs.insert(i, format!("value number {}", i)).unwrap();
+        }
+        bs.insert(1, "a new value for one").unwrap();
+        let before = contents(&mut bs);
+        drop(bs);
+
+        type Op = fn(&mut BlobStore) -> Result<(), BlobError>;
+        let ops: Vec<Op> = vec![
+            |bs| bs.insert(8, "a new one that goes in the log").map(|_| ()),
+            |bs| bs.remove(&2),
+            |bs| bs.gc_value_log(),
+        ];
+        for op in ops {
+            let start = |budget| {
+                remove_store(fs);
+                std::fs::copy(base, fs).unwrap();
+                std::fs::copy(vlog_name(base, 0), vlog_name(fs, 0)).unwrap();
+                let mut bs = BlobStore::open(fs).unwrap().with_journal().unwrap();
+                bs.fail_after = Some(budget);
+                bs
+            };
+            let mut bs = start(u64::MAX);
+            op(&mut bs).unwrap();
+            let total = u64::MAX - bs.fail_after.unwrap();
+            let after = contents(&mut bs);
+            // once it is done, it stays done
+            lose_unsynced(bs);
+            assert_eq!(contents(&mut BlobStore::open(fs).unwrap()), after);
+
+            for n in 0..total {
+                let mut bs = start(n);
+                assert!(op(&mut bs).is_err());
+                lose_unsynced(bs);
+                let mut bs = BlobStore::open(fs).unwrap();
+                assert_eq!(contents(&mut bs), before, "crash after {} bytes", n);
+                // and the log that is left is still good for the change
+                op(&mut bs).unwrap();
+                assert_eq!(contents(&mut bs), after);
+            }
+        }
     }
 }
@@ -0,0 +1,88 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::BlobError;
+
+/// Turns keys and values into the bytes stored in a blob and back
+pub trait Codec<T> {
+    fn encode(t: &T) -> Result<Vec<u8>, BlobError>;
+    fn decode(b: &[u8]) -> Result<T, BlobError>;
+}
+
+/// Which codec a file was written with, kept in its header
+pub trait CodecId {
+    const ID: u64;
+}
+
+/// What the plain BlobStore methods use
+pub struct Bincode;
+pub struct Json;
+pub struct MsgPack;
+/// No encoding at all, for values that are bytes already
+pub struct Raw;
+
+impl CodecId for Bincode {
+    const ID: u64 = 1;
+}
+
+impl CodecId for Json {
+    const ID: u64 = 2;
+}
+
+impl CodecId for MsgPack {
+    const ID: u64 = 3;
+}
+
+impl CodecId for Raw {
+    const ID: u64 = 4;
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    fn encode(t: &T) -> Result<Vec<u8>, BlobError> {
+        Ok(bincode::serialize(t)?)
+    }
+
+    fn decode(b: &[u8]) -> Result<T, BlobError> {
+        Ok(bincode::deserialize(b)?)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+    fn encode(t: &T) -> Result<Vec<u8>, BlobError> {
+        serde_json::to_vec(t).map_err(|e| BlobError::Codec(e.to_string()))
+    }
+
+    fn decode(b: &[u8]) -> Result<T, BlobError> {
+        serde_json::from_slice(b).map_err(|e| BlobError::Codec(e.to_string()))
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for MsgPack {
+    fn encode(t: &T) -> Result<Vec<u8>, BlobError> {
+        rmp_serde::to_vec(t).map_err(|e| BlobError::Codec(e.to_string()))
+    }
+
+    fn decode(b: &[u8]) -> Result<T, BlobError> {
+        rmp_serde::from_slice(b).map_err(|e| BlobError::Codec(e.to_string()))
+    }
+}
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(t: &Vec<u8>) -> Result<Vec<u8>, BlobError> {
+        Ok(t.clone())
+    }
+
+    fn decode(b: &[u8]) -> Result<Vec<u8>, BlobError> {
+        Ok(b.to_vec())
+    }
+}
+
+impl Codec<String> for Raw {
+    fn encode(t: &String) -> Result<Vec<u8>, BlobError> {
+        Ok(t.as_bytes().to_vec())
+    }
+
+    fn decode(b: &[u8]) -> Result<String, BlobError> {
+        String::from_utf8(b.to_vec()).map_err(|e| BlobError::Codec(e.to_string()))
+    }
+}
@@ -1,21 +1,66 @@
-use failure_derive::*;
+use std::fmt;
 
-#[derive(Fail, Debug)]
+#[derive(Debug)]
 pub enum BlobError {
-    #[fail(display = "No Room")]
     NoRoom,
-    #[fail(display = "Too Big {}", 0)] // 0表示TooBig(u64)里面的u64这个参数
     TooBig(u64),
-    #[fail(display = "Not Found")]
     NotFound,
-    #[fail(display = "BinCode {}", 0)]
+    /// The key encodes to no bytes, which can not be told from free space
+    EmptyKey,
+    Corrupt { offset: u64, reason: String },
+    CodecMismatch { found: u64, expected: u64 },
+    Locked,
+    ReadOnly,
+    /// A change failed with error and putting back what it wrote
+    /// failed too, the journal still has it for the next open
+    RollbackFailed {
+        error: Box<BlobError>,
+        rollback: Box<BlobError>,
+    },
+    Codec(String),
     Bincode(bincode::Error),
-    #[fail(display = "IO {}", 0)]
     IO(std::io::Error),
-    #[fail(display = "Failure {}", 0)]
     Failure(failure::Error),
 }
 
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlobError::NoRoom => write!(f, "No Room"),
+            // the block size that would have been needed
+            BlobError::TooBig(n) => write!(f, "Too Big {}", n),
+            BlobError::NotFound => write!(f, "Not Found"),
+            BlobError::EmptyKey => write!(f, "Key encodes to nothing"),
+            BlobError::Corrupt { offset, reason } => write!(f, "Corrupt at {}: {}", offset, reason),
+            BlobError::CodecMismatch { found, expected } => write!(
+                f,
+                "File uses codec {} but was opened with {}",
+                found, expected
+            ),
+            BlobError::Locked => write!(f, "File is locked by another handle"),
+            BlobError::ReadOnly => write!(f, "Store is open read only"),
+            BlobError::RollbackFailed { error, rollback } => {
+                write!(f, "{}, and undoing it failed: {}", error, rollback)
+            }
+            BlobError::Codec(e) => write!(f, "Codec {}", e),
+            BlobError::Bincode(e) => write!(f, "BinCode {}", e),
+            BlobError::IO(e) => write!(f, "IO {}", e),
+            BlobError::Failure(e) => write!(f, "Failure {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlobError::RollbackFailed { error, .. } => Some(error.as_ref()),
+            BlobError::Bincode(e) => Some(e.as_ref()),
+            BlobError::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl From<bincode::Error> for BlobError {
     fn from(e: bincode::Error) -> Self {
         BlobError::Bincode(e)
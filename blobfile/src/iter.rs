@@ -0,0 +1,46 @@
+use crate::blob::Blob;
+use crate::blobstore::BlobStore;
+use crate::error::BlobError;
+
+/// Goes through a BlobStore one bucket at a time, so only one
+/// bucket's blobs are ever held in memory.
+/// After an error it ends, as the rest of the file can not be trusted.
+pub struct BlobIter<'a> {
+    store: &'a BlobStore,
+    bucket: u64,
+    blobs: std::vec::IntoIter<Blob>,
+}
+
+impl<'a> BlobIter<'a> {
+    pub(crate) fn new(store: &'a BlobStore) -> Self {
+        BlobIter {
+            store,
+            bucket: 0,
+            blobs: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for BlobIter<'_> {
+    type Item = Result<Blob, BlobError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(b) = self.blobs.next() {
+                return Some(Ok(b));
+            }
+            if self.bucket >= self.store.nblocks() {
+                return None;
+            }
+            match self.store.bucket_blobs(self.bucket) {
+                Ok(v) => {
+                    self.blobs = v.into_iter();
+                    self.bucket += 1;
+                }
+                Err(e) => {
+                    self.bucket = self.store.nblocks();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+
+use crate::blob::{read_u64, write_u64};
+use crate::error::BlobError;
+
+pub fn journal_name(fname: &str) -> String {
+    format!("{}.journal", fname)
+}
+
+// Every write to either file goes through here. They are positional,
+// so handles sharing one file's offset can write side by side.
+// budget is how many more bytes may be written before we pretend to crash,
+// it is only ever set by the crash tests.
+pub(crate) fn write_at(
+    f: &File,
+    pos: u64,
+    data: &[u8],
+    budget: &mut Option<u64>,
+) -> Result<(), BlobError> {
+    if let Some(left) = budget {
+        if data.len() as u64 > *left {
+            f.write_all_at(&data[..*left as usize], pos)?;
+            *left = 0;
+            return Err(std::io::Error::other("crash test").into());
+        }
+        *left -= data.len() as u64;
+    }
+    f.write_all_at(data, pos)?;
+    Ok(())
+}
+
+// pos of an entry that holds the store's length before it grew
+const LEN_MARK: u64 = u64::MAX;
+
+// crc32 of an entry's pos, len and old bytes
+fn check(pos: u64, old: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(&pos.to_le_bytes());
+    h.update(&(old.len() as u64).to_le_bytes());
+    h.update(old);
+    h.finalize()
+}
+
+/// An undo journal: before any part of the store is written over,
+/// what was there is saved here and synced. When a change is done
+/// the journal is emptied, so if it is not empty on open the last change
+/// never finished and putting back what is saved undoes it.
+///
+/// Each entry is pos, len, the old bytes and a crc32 of all that,
+/// or for the file getting longer, LEN_MARK and the old length as the bytes.
+/// an entry cut short by a crash is never complete, and its write to the
+/// store never started, so it is just left off.
+pub(crate) struct Journal {
+    file: File,
+    end: u64,
+}
+
+impl Journal {
+    /// Starts an empty journal, anything already there is thrown away
+    pub fn create(fname: &str) -> Result<Self, BlobError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .read(true)
+            .open(fname)?;
+        Ok(Journal { file, end: 0 })
+    }
+
+    pub fn open(fname: &str) -> Result<Self, BlobError> {
+        let mut file = OpenOptions::new().write(true).read(true).open(fname)?;
+        let end = file.seek(SeekFrom::End(0))?;
+        Ok(Journal { file, end })
+    }
+
+    /// Saves what is at pos in f before it gets written over
+    pub fn record(
+        &mut self,
+        f: &File,
+        pos: u64,
+        len: u64,
+        budget: &mut Option<u64>,
+    ) -> Result<(), BlobError> {
+        let mut old = vec![0u8; len as usize];
+        f.read_exact_at(&mut old, pos)?;
+        self.add(pos, &old, budget)
+    }
+
+    /// Saves how long f is before it is made longer, undoing cuts it back
+    pub fn record_len(&mut self, f: &File, budget: &mut Option<u64>) -> Result<(), BlobError> {
+        let flen = f.metadata()?.len();
+        self.add(LEN_MARK, &flen.to_le_bytes(), budget)
+    }
+
+    fn add(&mut self, pos: u64, old: &[u8], budget: &mut Option<u64>) -> Result<(), BlobError> {
+        let len = old.len() as u64;
+        let mut ent = Vec::with_capacity(20 + old.len());
+        write_u64(&mut ent, pos)?;
+        write_u64(&mut ent, len)?;
+        ent.extend_from_slice(old);
+        ent.extend_from_slice(&check(pos, old).to_le_bytes());
+        write_at(&self.file, self.end, &ent, budget)?;
+        // must be on disk before the store is touched
+        self.file.sync_data()?;
+        self.end += ent.len() as u64;
+        Ok(())
+    }
+
+    /// The change went through, nothing to undo any more
+    pub fn clear(&mut self, f: &mut File) -> Result<(), BlobError> {
+        // the change must be on disk before the way back is gone
+        f.sync_data()?;
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.end = 0;
+        Ok(())
+    }
+
+    /// Puts back everything saved, newest first so the oldest
+    /// copy of any part written twice is the one left
+    pub fn rollback(&mut self, f: &mut File, budget: &mut Option<u64>) -> Result<(), BlobError> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut data)?;
+
+        let mut ents = Vec::new();
+        let mut r = &data[..];
+        while r.len() >= 16 {
+            let pos = read_u64(&mut r)?;
+            let len = read_u64(&mut r)?;
+            if (r.len() as u64) < len + 4 {
+                break;
+            }
+            let (old, rest) = r.split_at(len as usize);
+            let (crc, rest) = rest.split_at(4);
+            r = rest;
+            if u32::from_le_bytes(crc.try_into().unwrap()) != check(pos, old) {
+                break;
+            }
+            ents.push((pos, old));
+        }
+        for (pos, old) in ents.into_iter().rev() {
+            match pos {
+                LEN_MARK if old.len() == 8 => {
+                    f.set_len(u64::from_le_bytes(old.try_into().unwrap()))?
+                }
+                _ => write_at(f, pos, old, budget)?,
+            }
+        }
+        self.clear(f)
+    }
+}
@@ -1,6 +1,14 @@
+pub mod batch;
 pub mod blob;
+pub mod blobmap;
 pub mod blobstore;
+pub mod codec;
 pub mod error;
+pub mod iter;
+mod journal;
+pub mod shared;
+pub mod typed;
+mod vlog;
 
 #[cfg(test)]
 mod tests {}
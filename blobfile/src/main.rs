@@ -0,0 +1,457 @@
+// A command line tool to look inside and fix up blob files
+// run with: cargo run -p blobfile -- <command> <file> ...
+
+use std::io::Write;
+
+use blobfile::blobstore::BlobStore;
+use blobfile::codec::{Bincode, Codec, CodecId, Json, MsgPack, Raw};
+use blobfile::error::BlobError;
+use serde_json::Value;
+
+const USAGE: &str = "usage: blobfile <command> <file> [args] [options]
+
+commands:
+  info <file>                 print the header
+  check <file>                walk every bucket and check what is there
+  dump <file>                 print every record
+  get <file> <key>            print the value for key
+  put <file> <key> <value>    put a pair in, replacing any value for key
+  rm <file> <key>             take key out
+  compact <file>              close up the holes in every bucket, gc the value log
+
+options:
+  --hex            print keys and values as hex rather than decoded
+  --key <type>     type of the keys in a bincode file (default str)
+  --value <type>   type of the values in a bincode file (default str)
+
+types: str i32 i64 u32 u64 f64 bool
+files written with json or msgpack take keys and values as json,
+raw files take them as text";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args, &mut std::io::stdout()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+// bincode does not say what type anything is, so it has to be told
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Str,
+    I32,
+    I64,
+    U32,
+    U64,
+    F64,
+    Bool,
+}
+
+impl Kind {
+    fn parse(s: &str) -> Result<Kind, String> {
+        Ok(match s {
+            "str" => Kind::Str,
+            "i32" => Kind::I32,
+            "i64" => Kind::I64,
+            "u32" => Kind::U32,
+            "u64" => Kind::U64,
+            "f64" => Kind::F64,
+            "bool" => Kind::Bool,
+            _ => return Err(format!("unknown type {}", s)),
+        })
+    }
+
+    fn encode(self, s: &str) -> Result<Vec<u8>, String> {
+        fn p<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+            s.parse()
+                .map_err(|_| format!("can not read {:?} as that type", s))
+        }
+        let res = match self {
+            Kind::Str => bincode::serialize(s),
+            Kind::I32 => bincode::serialize(&p::<i32>(s)?),
+            Kind::I64 => bincode::serialize(&p::<i64>(s)?),
+            Kind::U32 => bincode::serialize(&p::<u32>(s)?),
+            Kind::U64 => bincode::serialize(&p::<u64>(s)?),
+            Kind::F64 => bincode::serialize(&p::<f64>(s)?),
+            Kind::Bool => bincode::serialize(&p::<bool>(s)?),
+        };
+        res.map_err(|e| e.to_string())
+    }
+
+    fn decode(self, b: &[u8]) -> Result<Value, BlobError> {
+        Ok(match self {
+            Kind::Str => Value::from(<Bincode as Codec<String>>::decode(b)?),
+            Kind::I32 => Value::from(<Bincode as Codec<i32>>::decode(b)?),
+            Kind::I64 => Value::from(<Bincode as Codec<i64>>::decode(b)?),
+            Kind::U32 => Value::from(<Bincode as Codec<u32>>::decode(b)?),
+            Kind::U64 => Value::from(<Bincode as Codec<u64>>::decode(b)?),
+            Kind::F64 => Value::from(<Bincode as Codec<f64>>::decode(b)?),
+            Kind::Bool => Value::from(<Bincode as Codec<bool>>::decode(b)?),
+        })
+    }
+}
+
+struct Args {
+    cmd: String,
+    file: String,
+    rest: Vec<String>,
+    hex: bool,
+    key: Kind,
+    value: Kind,
+}
+
+fn parse(args: &[String]) -> Result<Args, String> {
+    let mut pos = Vec::new();
+    let mut hex = false;
+    let mut key = Kind::Str;
+    let mut value = Kind::Str;
+    let mut it = args.iter();
+    while let Some(a) = it.next() {
+        match a.as_str() {
+            "--hex" => hex = true,
+            "--key" | "--value" => {
+                let t = it.next().ok_or(format!("{} needs a type", a))?;
+                match a.as_str() {
+                    "--key" => key = Kind::parse(t)?,
+                    _ => value = Kind::parse(t)?,
+                }
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if a.starts_with("--") => return Err(format!("unknown option {}\n\n{}", a, USAGE)),
+            _ => pos.push(a.clone()),
+        }
+    }
+    if pos.len() < 2 {
+        return Err(USAGE.to_string());
+    }
+    let rest = pos.split_off(2);
+    let want = match pos[0].as_str() {
+        "info" | "check" | "dump" | "compact" => 0,
+        "get" | "rm" => 1,
+        "put" => 2,
+        c => return Err(format!("unknown command {}\n\n{}", c, USAGE)),
+    };
+    if rest.len() != want {
+        return Err(format!(
+            "{} takes {} argument(s) after the file",
+            pos[0], want
+        ));
+    }
+    Ok(Args {
+        cmd: pos[0].clone(),
+        file: pos[1].clone(),
+        rest,
+        hex,
+        key,
+        value,
+    })
+}
+
+fn codec_name(id: u64) -> &'static str {
+    match id {
+        Bincode::ID => "bincode",
+        Json::ID => "json",
+        MsgPack::ID => "msgpack",
+        Raw::ID => "raw",
+        _ => "unknown",
+    }
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// text from the command line into the bytes the file keeps
+fn encode(codec: u64, kind: Kind, s: &str) -> Result<Vec<u8>, String> {
+    let json =
+        || serde_json::from_str::<Value>(s).map_err(|e| format!("{:?} is not json: {}", s, e));
+    let res = match codec {
+        Bincode::ID => return kind.encode(s),
+        Json::ID => <Json as Codec<Value>>::encode(&json()?),
+        MsgPack::ID => <MsgPack as Codec<Value>>::encode(&json()?),
+        Raw::ID => return Ok(s.as_bytes().to_vec()),
+        c => return Err(format!("can not encode for codec {}", c)),
+    };
+    res.map_err(|e| e.to_string())
+}
+
+// bytes from the file as json, or as hex if they will not decode
+fn decode(codec: u64, kind: Kind, b: &[u8]) -> Value {
+    let res = match codec {
+        Bincode::ID => kind.decode(b),
+        Json::ID => <Json as Codec<Value>>::decode(b),
+        MsgPack::ID => <MsgPack as Codec<Value>>::decode(b),
+        Raw::ID => String::from_utf8(b.to_vec())
+            .map(Value::from)
+            .map_err(|e| BlobError::Codec(e.to_string())),
+        c => Err(BlobError::Codec(format!("unknown codec {}", c))),
+    };
+    res.unwrap_or_else(|_| Value::from(format!("0x{}", hex(b))))
+}
+
+fn run(args: &[String], out: &mut dyn Write) -> Result<(), String> {
+    let a = parse(args)?;
+    let err = |e: BlobError| format!("{}: {}", a.file, e);
+    let io = |e: std::io::Error| e.to_string();
+    // anything that changes the file needs it to itself
+    let mut bs = match a.cmd.as_str() {
+        "put" | "rm" | "compact" => BlobStore::open(&a.file),
+        _ => BlobStore::open_read_only(&a.file),
+    }
+    .map_err(err)?;
+    let codec = bs.codec();
+    let show = |b: &[u8], kind: Kind| match a.hex {
+        true => hex(b),
+        false => decode(codec, kind, b).to_string(),
+    };
+    // the key from the command line, encoded the way the file is.
+    // One that encodes to nothing could not be told from free space.
+    let key = || match encode(codec, a.key, &a.rest[0])? {
+        k if k.is_empty() => Err(format!(
+            "{:?} encodes to no bytes with codec {}, keys can not be empty",
+            a.rest[0],
+            codec_name(codec)
+        )),
+        k => Ok(k),
+    };
+
+    match a.cmd.as_str() {
+        "info" => {
+            let overflow = bs.total_blocks() - bs.nblocks();
+            writeln!(out, "file        {}", a.file).map_err(io)?;
+            writeln!(out, "seed        {}", bs.seed()).map_err(io)?;
+            writeln!(out, "block_size  {}", bs.block_size()).map_err(io)?;
+            writeln!(out, "nblocks     {}", bs.nblocks()).map_err(io)?;
+            writeln!(out, "elems       {}", bs.len()).map_err(io)?;
+            writeln!(out, "codec       {} ({})", codec, codec_name(codec)).map_err(io)?;
+            writeln!(out, "overflow    {} blocks", overflow).map_err(io)?;
+            if let Some((len, dead)) = bs.value_log_stats() {
+                writeln!(out, "value log   {} bytes, {} dead", len, dead).map_err(io)?;
+            }
+        }
+        "check" => check(&bs, out)?,
+        "dump" => {
+            for bucket in 0..bs.nblocks() {
+                let mut lines = Vec::new();
+                bs.for_each_in_bucket(bucket, |_, b| {
+                    lines.push(format!(
+                        "{}\t{}",
+                        show(b.k_bytes(), a.key),
+                        show(b.v_bytes(), a.value)
+                    ))
+                })
+                .map_err(err)?;
+                for l in lines {
+                    writeln!(out, "{}", l).map_err(io)?;
+                }
+            }
+        }
+        "get" => {
+            let b = bs.get_raw(&key()?, codec).map_err(err)?;
+            writeln!(out, "{}", show(b.v_bytes(), a.value)).map_err(io)?;
+        }
+        "put" => {
+            let v = encode(codec, a.value, &a.rest[1])?;
+            bs.insert_raw(&key()?, &v, codec).map_err(err)?;
+        }
+        "rm" => {
+            if bs.remove_raw(&key()?, codec).map_err(err)?.is_none() {
+                return Err(err(BlobError::NotFound));
+            }
+        }
+        "compact" => {
+            let holes = |bs: &BlobStore| -> Result<u64, String> {
+                let mut n = 0;
+                for bucket in 0..bs.nblocks() {
+                    n += bs.bucket_stats(bucket).map_err(err)?.free_sections;
+                }
+                Ok(n)
+            };
+            let before = holes(&bs)?;
+            bs.compact_all().map_err(err)?;
+            writeln!(out, "free sections {} -> {}", before, holes(&bs)?).map_err(io)?;
+            if let Some((len, _)) = bs.value_log_stats() {
+                bs.gc_value_log().map_err(err)?;
+                let now = bs.value_log_stats().unwrap().0;
+                writeln!(out, "value log {} -> {} bytes", len, now).map_err(io)?;
+            }
+        }
+        _ => unreachable!("parse only lets through known commands"),
+    }
+    Ok(())
+}
+
+// Every bucket is walked on its own, so one bad one does not hide the rest.
+// Walking checks the links between blocks, that every length (free sections
+// too) stays inside its block, and every record's checksum.
+fn check(bs: &BlobStore, out: &mut dyn Write) -> Result<(), String> {
+    let io = |e: std::io::Error| e.to_string();
+    let mut problems = 0;
+    let mut pairs = 0;
+    let mut linked = 0;
+    for bucket in 0..bs.nblocks() {
+        let res = bs
+            .bucket_stats(bucket)
+            .and_then(|st| bs.for_each_in_bucket(bucket, |_, _| {}).map(|_| st));
+        match res {
+            Ok(st) => {
+                pairs += st.live;
+                linked += st.blocks;
+            }
+            Err(e) => {
+                problems += 1;
+                writeln!(out, "bucket {}: {}", bucket, e).map_err(io)?;
+            }
+        }
+    }
+    if problems == 0 && pairs != bs.len() {
+        problems += 1;
+        writeln!(out, "header says {} pairs, found {}", bs.len(), pairs).map_err(io)?;
+    }
+    // a change rolled back after adding a block leaves it there unlinked,
+    // that wastes space but is not wrong
+    if problems == 0 && linked < bs.total_blocks() {
+        let n = bs.total_blocks() - linked;
+        writeln!(out, "{} overflow blocks not linked from any bucket", n).map_err(io)?;
+    }
+    if problems > 0 {
+        return Err(format!("{} problem(s) found", problems));
+    }
+    writeln!(out, "ok: {} pairs in {} blocks", pairs, linked).map_err(io)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blobfile::typed::TypedBlobStore;
+
+    fn run_ok(args: &str) -> String {
+        let args: Vec<String> = args.split(' ').map(|s| s.to_string()).collect();
+        let mut out = Vec::new();
+        if let Err(e) = run(&args, &mut out) {
+            panic!("{}: {}", args.join(" "), e);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn run_err(args: &str) -> String {
+        let args: Vec<String> = args.split(' ').map(|s| s.to_string()).collect();
+        run(&args, &mut Vec::new()).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(run_err("info").starts_with("usage"));
+        assert!(run_err("frob f").starts_with("unknown command frob"));
+        assert!(run_err("get f").contains("takes 1"));
+        assert!(run_err("get f k --key i8").contains("unknown type i8"));
+        assert!(run_err("get f k --key").contains("needs a type"));
+        let a = parse(&["put", "f", "--key", "i32", "1", "x", "--hex"].map(String::from)).unwrap();
+        assert_eq!((a.key, a.value, a.hex), (Kind::I32, Kind::Str, true));
+        assert_eq!(a.rest, vec!["1", "x"]);
+    }
+
+    #[test]
+    fn test_bincode_file() {
+        let fs = "test_data/cli_bincode";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 2).unwrap();
+        bs.insert(1, "one".to_string()).unwrap();
+        bs.insert(2, "two".to_string()).unwrap();
+        drop(bs);
+
+        let info = run_ok(&format!("info {}", fs));
+        assert!(info.contains("block_size  200"));
+        assert!(info.contains("elems       2"));
+        assert!(info.contains("codec       1 (bincode)"));
+
+        run_ok(&format!("put {} 3 three --key i32", fs));
+        run_ok(&format!("rm {} 1 --key i32", fs));
+        assert!(run_err(&format!("rm {} 1 --key i32", fs)).contains("Not Found"));
+        assert_eq!(run_ok(&format!("get {} 3 --key i32", fs)), "\"three\"\n");
+        assert_eq!(
+            run_ok(&format!("get {} 3 --key i32 --hex", fs)),
+            "05000000000000007468726565\n"
+        );
+
+        let mut dump: Vec<String> = run_ok(&format!("dump {} --key i32", fs))
+            .lines()
+            .map(|s| s.to_string())
+            .collect();
+        dump.sort();
+        assert_eq!(dump, vec!["2\t\"two\"", "3\t\"three\""]);
+        // the wrong type comes out as hex rather than failing
+        assert!(run_ok(&format!("dump {} --key bool", fs)).contains("0x02000000"));
+
+        assert_eq!(
+            run_ok(&format!("check {}", fs)),
+            "ok: 2 pairs in 2 blocks\n"
+        );
+        assert!(run_ok(&format!("compact {}", fs)).starts_with("free sections"));
+        let bs = BlobStore::open(fs).unwrap();
+        assert_eq!(bs.get(&3).unwrap().get_v::<String>().unwrap(), "three");
+    }
+
+    #[test]
+    fn test_json_file() {
+        let fs = "test_data/cli_json";
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<String, Vec<u32>, Json> =
+            TypedBlobStore::new(fs, 200, 2).unwrap();
+        ts.insert(&"a".to_string(), &vec![1, 2]).unwrap();
+        drop(ts);
+
+        assert!(run_ok(&format!("info {}", fs)).contains("(json)"));
+        assert_eq!(run_ok(&format!("get {} \"a\"", fs)), "[1,2]\n");
+        run_ok(&format!("put {} \"b\" [3]", fs));
+        assert!(run_err(&format!("put {} b [3]", fs)).contains("not json"));
+        let ts: TypedBlobStore<String, Vec<u32>, Json> = TypedBlobStore::open(fs).unwrap();
+        assert_eq!(ts.get(&"b".to_string()).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn test_raw_empty_key() {
+        let fs = "test_data/cli_raw";
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<String, String, Raw> = TypedBlobStore::new(fs, 200, 1).unwrap();
+        ts.insert(&"a".to_string(), &"x".to_string()).unwrap();
+        drop(ts);
+
+        // split on spaces, so two in a row are an empty key
+        let e = run_err(&format!("put {}  v", fs));
+        assert!(e.contains("keys can not be empty"), "{}", e);
+        assert!(run_err(&format!("get {} ", fs)).contains("keys can not be empty"));
+        run_ok(&format!("put {} b y", fs));
+        assert_eq!(run_ok(&format!("get {} b", fs)), "\"y\"\n");
+        assert_eq!(
+            run_ok(&format!("check {}", fs)),
+            "ok: 2 pairs in 1 blocks\n"
+        );
+        // and the store itself turns it away for anyone else
+        let mut bs = BlobStore::open(fs).unwrap();
+        let res = bs.insert_raw(b"", b"v", Raw::ID);
+        assert!(matches!(res, Err(BlobError::EmptyKey)));
+    }
+
+    #[test]
+    fn test_check_finds_damage() {
+        let fs = "test_data/cli_check";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 1).unwrap();
+        bs.insert(1, "some value").unwrap();
+        drop(bs);
+        // flip a byte in the value, its checksum will not match
+        let mut data = std::fs::read(fs).unwrap();
+        let at = data.windows(10).position(|w| w == b"some value").unwrap();
+        data[at] = b'S';
+        std::fs::write(fs, data).unwrap();
+
+        let args: Vec<String> = vec!["check".to_string(), fs.to_string()];
+        let mut out = Vec::new();
+        assert_eq!(run(&args, &mut out).unwrap_err(), "1 problem(s) found");
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("bucket 0: Corrupt at"), "{}", out);
+    }
+}
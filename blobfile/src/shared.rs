@@ -0,0 +1,436 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::Serialize;
+
+use crate::blob::Blob;
+use crate::blobstore::{BlobStore, SharedHeader, SyncPolicy};
+use crate::error::BlobError;
+
+/// A BlobStore that can be used from many threads through &self,
+/// with any number of readers and a writer per bucket.
+///
+/// Reads go through a read only handle on the file using positional
+/// reads, so they never wait on each other. Each bucket has its own lock:
+/// a change takes it to write, so readers of that bucket wait while it is
+/// changed and readers of every other bucket carry on. The change itself
+/// goes through a handle of its own, writing at fixed positions, so changes
+/// to different buckets run side by side. What they all share, the count
+/// in the header, the dead bytes of the value log and where the next
+/// overflow block or log entry goes, is kept behind one small lock that
+/// is only held while those are read and written.
+/// A remove of a key that is not there only reads, so it does not wait.
+///
+/// An undo journal only covers one change at a time, so a store with
+/// one still makes its changes one after another, through the store
+/// itself. Each bucket is still only locked while it is changed.
+///
+/// A value log gc moves records in every bucket, so `gc_value_log`
+/// waits for all of them to be free. It is only ever run when asked for.
+pub struct SharedBlobStore {
+    // the store as it was handed in: it makes the changes when it has a
+    // journal, runs gc, and is what into_inner gives back
+    store: Mutex<BlobStore>,
+    // only its file length and value log ever change, when a change
+    // adds a block or gc makes a new log
+    reader: RwLock<BlobStore>,
+    buckets: Vec<RwLock<()>>,
+    header: Arc<Mutex<SharedHeader>>,
+    sync: SyncPolicy,
+    journal: bool,
+}
+
+impl SharedBlobStore {
+    pub fn new(mut store: BlobStore) -> Result<Self, BlobError> {
+        let reader = store.reader()?;
+        let buckets = (0..store.nblocks()).map(|_| RwLock::new(())).collect();
+        Ok(SharedBlobStore {
+            header: store.share_header(),
+            sync: store.sync_policy(),
+            journal: store.has_journal(),
+            store: Mutex::new(store),
+            reader: RwLock::new(reader),
+            buckets,
+        })
+    }
+
+    pub fn open(fname: &str) -> Result<Self, BlobError> {
+        Self::new(BlobStore::open(fname)?)
+    }
+
+    pub fn get<K: Serialize>(&self, k: &K) -> Result<Blob, BlobError> {
+        let s_blob = self.reader.read().unwrap().blob_from(k, &0)?;
+        let _b = self.buckets[self.bucket_of(&s_blob)].read().unwrap();
+        self.reader.read().unwrap().get_blob(&s_blob)
+    }
+
+    /// Puts the pair in, replacing the value if the key is already there.
+    /// Returns the blob that was replaced.
+    pub fn insert<K: Serialize, V: Serialize>(
+        &self,
+        k: K,
+        v: V,
+    ) -> Result<Option<Blob>, BlobError> {
+        let blob = self.reader.read().unwrap().blob_from(&k, &v)?;
+        let _b = self.buckets[self.bucket_of(&blob)].write().unwrap();
+        self.change(|s| s.replace_blob(&blob))
+    }
+
+    pub fn remove<K: Serialize>(&self, k: &K) -> Result<Option<Blob>, BlobError> {
+        let s_blob = self.reader.read().unwrap().blob_from(k, &0)?;
+        let _b = self.buckets[self.bucket_of(&s_blob)].write().unwrap();
+        // no one else can change the bucket while we have it locked,
+        // so if the reader can not see the key it is not there
+        if !self.reader.read().unwrap().has_blob(&s_blob)? {
+            return Ok(None);
+        }
+        self.change(|s| s.remove_blob(&s_blob))
+    }
+
+    // The reader lock is only ever held for a moment on its own,
+    // or taken after a bucket lock, never the other way round
+    fn bucket_of(&self, blob: &Blob) -> usize {
+        self.reader.read().unwrap().bucket_of(blob) as usize
+    }
+
+    // Runs f on a handle of its own, or on the store once every change
+    // before it is done if it has a journal. The caller has the bucket f
+    // changes locked already. Always the bucket, then the store, then the
+    // reader, so no two threads can each hold what the other wants. The
+    // header lock is taken on its own and let go before anything else.
+    fn change<R, F>(&self, f: F) -> Result<R, BlobError>
+    where
+        F: FnOnce(&mut BlobStore) -> Result<R, BlobError>,
+    {
+        if self.journal {
+            let mut w = self.store.lock().unwrap();
+            let res = f(&mut w);
+            self.catch_up(&w)?;
+            return res;
+        }
+        let mut w = self.reader.read().unwrap().writer(&self.header, self.sync)?;
+        let res = f(&mut w);
+        // a new overflow block is only linked in under our bucket lock,
+        // so the reader can find out about it before anyone follows the link
+        self.catch_up(&w)?;
+        res
+    }
+
+    fn catch_up(&self, w: &BlobStore) -> Result<(), BlobError> {
+        let behind = {
+            let r = self.reader.read().unwrap();
+            r.flen() < w.flen() || r.vlog_gen() != w.vlog_gen()
+        };
+        if behind {
+            self.reader.write().unwrap().catch_up(w)?;
+        }
+        Ok(())
+    }
+
+    /// True once half the value log is dead
+    pub fn gc_due(&self) -> bool {
+        let mut w = self.store.lock().unwrap();
+        w.load_shared().is_ok() && w.gc_due()
+    }
+
+    /// Takes every bucket lock in order, then the store as a change does,
+    /// so nobody is reading a record while gc points it somewhere else
+    pub fn gc_value_log(&self) -> Result<(), BlobError> {
+        let _all: Vec<_> = self.buckets.iter().map(|b| b.write().unwrap()).collect();
+        let mut w = self.store.lock().unwrap();
+        w.load_shared()?;
+        w.gc_value_log()?;
+        self.catch_up(&w)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.header.lock().unwrap().elems()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The store, with what every change made to it
+    pub fn into_inner(self) -> Result<BlobStore, BlobError> {
+        let mut w = self.store.into_inner().unwrap();
+        w.load_shared()?;
+        w.unshare_header();
+        Ok(w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keys that hash to the bucket given and to any other
+    fn keys_by_bucket(ss: &SharedBlobStore, bucket: usize) -> (Vec<i32>, Vec<i32>) {
+        (0..200).partition(|k| {
+            let b = ss.reader.read().unwrap().blob_from(k, &0).unwrap();
+            ss.bucket_of(&b) == bucket
+        })
+    }
+
+    // With one bucket being changed, reads, inserts and removes in every
+    // other bucket get through, only a change to the same bucket waits
+    #[test]
+    fn test_bucket_writers_overlap() {
+        let fs = "test_data/shared_overlap";
+        std::fs::remove_file(fs).ok();
+        let ss = SharedBlobStore::new(BlobStore::new(fs, 200, 8).unwrap()).unwrap();
+        let (mine, others) = keys_by_bucket(&ss, 0);
+        ss.insert(mine[0], 0).unwrap();
+        let b = ss.buckets[0].write().unwrap();
+        std::thread::scope(|s| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let ss = &ss;
+            let others = &others;
+            s.spawn(move || {
+                for &k in &others[..20] {
+                    assert!(ss.insert(k, k * 10).unwrap().is_none());
+                    assert_eq!(ss.get(&k).unwrap().get_v::<i32>().unwrap(), k * 10);
+                }
+                for &k in &others[..10] {
+                    assert!(ss.remove(&k).unwrap().is_some());
+                }
+                tx.send(()).unwrap();
+            });
+            rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+
+            let t = s.spawn(|| ss.insert(mine[1], 1).unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!t.is_finished());
+            drop(b);
+            assert!(t.join().unwrap().is_none());
+        });
+        assert_eq!(ss.get(&mine[1]).unwrap().get_v::<i32>().unwrap(), 1);
+        assert_eq!(ss.len(), 12);
+        let bs = ss.into_inner().unwrap();
+        assert_eq!(bs.verify_count().unwrap(), 12);
+    }
+
+    // Writers on buckets of their own, all adding overflow blocks and
+    // value log entries at the same time. Each has to get room no one
+    // else got, and the count has to add up at the end.
+    #[test]
+    fn test_bucket_writers_grow_together() {
+        let fs = "test_data/shared_grow";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 256, 4)
+            .unwrap()
+            .with_value_log(64)
+            .unwrap();
+        bs.set_max_chain(20);
+        let ss = SharedBlobStore::new(bs).unwrap();
+        let value = |k: i32| format!("{}:", k).repeat(k as usize % 3 * 20 + 1);
+        let start = std::sync::Barrier::new(4);
+        std::thread::scope(|s| {
+            for bucket in 0..4 {
+                let (ss, start) = (&ss, &start);
+                s.spawn(move || {
+                    let (mine, _) = keys_by_bucket(ss, bucket);
+                    start.wait();
+                    for &k in &mine {
+                        assert!(ss.insert(k, value(k)).unwrap().is_none());
+                    }
+                    for &k in mine.iter().step_by(3) {
+                        assert!(ss.remove(&k).unwrap().is_some());
+                    }
+                });
+            }
+        });
+        let mut left = 0;
+        for k in 0..200 {
+            match ss.get(&k) {
+                Ok(b) => {
+                    assert_eq!(b.get_v::<String>().unwrap(), value(k));
+                    left += 1;
+                }
+                Err(BlobError::NotFound) => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert_eq!(ss.len(), left);
+        let bs = ss.into_inner().unwrap();
+        assert_eq!(bs.verify_count().unwrap(), left);
+        assert!(bs.total_blocks() > 4 * 3);
+        let (len, dead) = bs.value_log_stats().unwrap();
+        assert!(dead > 0 && dead < len);
+
+        // and it all reads back the same from the file
+        drop(bs);
+        let bs = BlobStore::open(fs).unwrap();
+        assert_eq!(bs.len(), left);
+        assert_eq!(bs.verify_count().unwrap(), left);
+    }
+
+    #[test]
+    fn test_shared_get_insert_remove() {
+        let fs = "test_data/shared_basic";
+        std::fs::remove_file(fs).ok();
+        get_insert_remove(BlobStore::new(fs, 200, 1).unwrap());
+        // with a journal the changes go through the store one at a time
+        let fs = "test_data/shared_basic_journal";
+        std::fs::remove_file(fs).ok();
+        let bs = BlobStore::new(fs, 200, 1).unwrap().with_journal().unwrap();
+        get_insert_remove(bs);
+    }
+
+    fn get_insert_remove(bs: BlobStore) {
+        let ss = SharedBlobStore::new(bs).unwrap();
+        // enough to need overflow blocks, which the reader has to follow
+        for i in 0..12 {
+            assert!(ss.insert(i, i * 10).unwrap().is_none());
+        }
+        for i in 0..12 {
+            assert_eq!(ss.get(&i).unwrap().get_v::<i32>().unwrap(), i * 10);
+        }
+        let old = ss.insert(3, 0).unwrap().unwrap();
+        assert_eq!(old.get_v::<i32>().unwrap(), 30);
+        assert_eq!(ss.remove(&4).unwrap().unwrap().get_v::<i32>().unwrap(), 40);
+        assert!(ss.remove(&4).unwrap().is_none());
+        assert!(matches!(ss.get(&4), Err(BlobError::NotFound)));
+        let bs = ss.into_inner().unwrap();
+        assert_eq!(bs.len(), 11);
+        assert!(bs.bucket_stats(0).unwrap().blocks > 1);
+        assert_eq!(bs.verify_count().unwrap(), 11);
+    }
+
+    // Writers each own a range of keys and keep changing them, readers look
+    // at random keys the whole time. A value is always key * 1000 + version,
+    // so a read that saw half a change would not match its key.
+    #[test]
+    fn test_shared_stress() {
+        let fs = "test_data/shared_stress";
+        std::fs::remove_file(fs).ok();
+        const WRITERS: i64 = 4;
+        const KEYS: i64 = 100;
+        const ROUNDS: i64 = 5;
+        let ss = Arc::new(SharedBlobStore::new(BlobStore::new(fs, 1024, 32).unwrap()).unwrap());
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let ss = ss.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let mut seen = 0;
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        let k = rand::random::<i64>().rem_euclid(WRITERS * KEYS);
+                        match ss.get(&k) {
+                            Ok(b) => {
+                                let v: i64 = b.get_v().unwrap();
+                                assert_eq!(v / 1000, k);
+                                seen += 1;
+                            }
+                            Err(BlobError::NotFound) => {}
+                            Err(e) => panic!("{}", e),
+                        }
+                    }
+                    seen
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|w| {
+                let ss = ss.clone();
+                std::thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        for k in w * KEYS..(w + 1) * KEYS {
+                            ss.insert(k, k * 1000 + round).unwrap();
+                        }
+                        // every other key goes and comes back
+                        for k in (w * KEYS..(w + 1) * KEYS).step_by(2) {
+                            assert!(ss.remove(&k).unwrap().is_some());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in writers {
+            t.join().unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        let seen: i64 = readers.into_iter().map(|t| t.join().unwrap()).sum();
+        assert!(seen > 0);
+
+        for k in 0..WRITERS * KEYS {
+            match k % 2 {
+                0 => assert!(matches!(ss.get(&k), Err(BlobError::NotFound))),
+                _ => {
+                    let v: i64 = ss.get(&k).unwrap().get_v().unwrap();
+                    assert_eq!(v, k * 1000 + ROUNDS - 1);
+                }
+            }
+        }
+        let ss = Arc::try_unwrap(ss).ok().unwrap();
+        assert_eq!(ss.len(), (WRITERS * KEYS / 2) as u64);
+        let bs = ss.into_inner().unwrap();
+        assert_eq!(bs.verify_count().unwrap(), bs.len());
+    }
+
+    // Big values keep being replaced so the value log is gc'd again and
+    // again, while readers check each value is the one for its key
+    #[test]
+    fn test_shared_value_log_gc() {
+        let fs = "test_data/shared_vlog";
+        std::fs::remove_file(fs).ok();
+        let bs = BlobStore::new(fs, 512, 8)
+            .unwrap()
+            .with_value_log(64)
+            .unwrap();
+        let ss = Arc::new(SharedBlobStore::new(bs).unwrap());
+        let value = |k: i64, round: i64| format!("{}:{}", k, round).repeat(100);
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let ss = ss.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        let k = rand::random::<i64>().rem_euclid(20);
+                        if let Ok(b) = ss.get(&k) {
+                            let v: String = b.get_v().unwrap();
+                            assert!(v.starts_with(&format!("{}:", k)));
+                        }
+                    }
+                })
+            })
+            .collect();
+        let writers: Vec<_> = (0..2)
+            .map(|w| {
+                let ss = ss.clone();
+                std::thread::spawn(move || {
+                    for round in 0..10 {
+                        for k in (w * 10)..(w + 1) * 10 {
+                            ss.insert(k, value(k, round)).unwrap();
+                        }
+                        if ss.gc_due() {
+                            ss.gc_value_log().unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in writers {
+            t.join().unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        for t in readers {
+            t.join().unwrap();
+        }
+
+        let ss = Arc::try_unwrap(ss).ok().unwrap();
+        for k in 0..20 {
+            let v: String = ss.get(&k).unwrap().get_v().unwrap();
+            assert_eq!(v, value(k, 9));
+        }
+        let bs = ss.into_inner().unwrap();
+        // 20 values live, at most as much again dead
+        let (len, dead) = bs.value_log_stats().unwrap();
+        assert!(dead * 2 < len);
+        assert!(bs.vlog_gen() > 0);
+    }
+}
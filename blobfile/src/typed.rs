@@ -0,0 +1,267 @@
+use std::marker::PhantomData;
+
+use crate::blob::Blob;
+use crate::blobstore::BlobStore;
+use crate::codec::{Bincode, Codec, CodecId};
+use crate::error::BlobError;
+
+/// A BlobStore that only holds K -> V, encoded with C.
+/// The codec is written in the file header when it is made,
+/// and open fails if it is not the one asked for.
+pub struct TypedBlobStore<K, V, C = Bincode> {
+    store: BlobStore,
+    _kvc: PhantomData<(K, V, C)>,
+}
+
+impl<K, V, C> TypedBlobStore<K, V, C>
+where
+    C: Codec<K> + Codec<V> + CodecId,
+{
+    pub fn new(fname: &str, block_size: u64, nblocks: u64) -> Result<Self, BlobError> {
+        let store = BlobStore::create(fname, block_size, nblocks, C::ID)?;
+        Ok(TypedBlobStore {
+            store,
+            _kvc: PhantomData,
+        })
+    }
+
+    pub fn open(fname: &str) -> Result<Self, BlobError> {
+        let store = BlobStore::open(fname)?;
+        if store.codec() != C::ID {
+            return Err(BlobError::CodecMismatch {
+                found: store.codec(),
+                expected: C::ID,
+            });
+        }
+        Ok(TypedBlobStore {
+            store,
+            _kvc: PhantomData,
+        })
+    }
+
+    fn blob(k: &K, v: &V) -> Result<Blob, BlobError> {
+        Ok(Blob::from_bytes(
+            <C as Codec<K>>::encode(k)?,
+            <C as Codec<V>>::encode(v)?,
+        ))
+    }
+
+    // only the key matters for finding or removing
+    fn key_blob(k: &K) -> Result<Blob, BlobError> {
+        Ok(Blob::from_bytes(<C as Codec<K>>::encode(k)?, Vec::new()))
+    }
+
+    fn value(b: &Blob) -> Result<V, BlobError> {
+        <C as Codec<V>>::decode(b.v_bytes())
+    }
+
+    /// Returns the value that was replaced
+    pub fn insert(&mut self, k: &K, v: &V) -> Result<Option<V>, BlobError> {
+        let old = self.store.replace_blob(&Self::blob(k, v)?)?;
+        old.as_ref().map(Self::value).transpose()
+    }
+
+    pub fn get(&self, k: &K) -> Result<V, BlobError> {
+        let b = self.store.get_blob(&Self::key_blob(k)?)?;
+        Self::value(&b)
+    }
+
+    pub fn contains_key(&self, k: &K) -> Result<bool, BlobError> {
+        match self.get(k) {
+            Ok(_) => Ok(true),
+            Err(BlobError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn remove(&mut self, k: &K) -> Result<Option<V>, BlobError> {
+        let old = self.store.remove_blob(&Self::key_blob(k)?)?;
+        old.as_ref().map(Self::value).transpose()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, V), BlobError>> + '_ {
+        self.store.iter_blobs().map(|r| {
+            let b = r?;
+            Ok((<C as Codec<K>>::decode(b.k_bytes())?, Self::value(&b)?))
+        })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Result<K, BlobError>> + '_ {
+        self.store
+            .iter_blobs()
+            .map(|r| <C as Codec<K>>::decode(r?.k_bytes()))
+    }
+
+    pub fn into_inner(self) -> BlobStore {
+        self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blobmap::BlobMap;
+    use crate::codec::{Json, MsgPack, Raw};
+    use serde_derive::*;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct Pet {
+        name: String,
+        legs: u8,
+    }
+
+    fn pets<C: Codec<String> + Codec<Pet> + CodecId>(fs: &str) {
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<String, Pet, C> = TypedBlobStore::new(fs, 400, 4).unwrap();
+        let rex = Pet {
+            name: "rex".to_string(),
+            legs: 4,
+        };
+        let tweety = Pet {
+            name: "tweety".to_string(),
+            legs: 2,
+        };
+        assert_eq!(ts.insert(&"dog".to_string(), &rex).unwrap(), None);
+        ts.insert(&"bird".to_string(), &rex).unwrap();
+        assert_eq!(ts.insert(&"bird".to_string(), &tweety).unwrap(), Some(rex.clone()));
+        drop(ts);
+
+        let mut ts: TypedBlobStore<String, Pet, C> = TypedBlobStore::open(fs).unwrap();
+        assert_eq!(ts.get(&"dog".to_string()).unwrap(), rex);
+        assert_eq!(ts.get(&"bird".to_string()).unwrap(), tweety);
+        assert!(matches!(ts.get(&"cat".to_string()), Err(BlobError::NotFound)));
+        assert_eq!(ts.len(), 2);
+        let mut all: Vec<String> = ts.iter().map(|r| r.unwrap().1.name).collect();
+        all.sort();
+        assert_eq!(all, vec!["rex", "tweety"]);
+        assert_eq!(ts.remove(&"dog".to_string()).unwrap(), Some(rex));
+        assert!(!ts.contains_key(&"dog".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_codecs() {
+        pets::<Bincode>("test_data/typed_bincode");
+        pets::<Json>("test_data/typed_json");
+        pets::<MsgPack>("test_data/typed_msgpack");
+    }
+
+    #[test]
+    fn test_json_is_json() {
+        let fs = "test_data/typed_json_bytes";
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<u32, Vec<u32>, Json> = TypedBlobStore::new(fs, 200, 1).unwrap();
+        ts.insert(&7, &vec![1, 2, 3]).unwrap();
+        let data = std::fs::read(fs).unwrap();
+        assert!(data.windows(7).any(|w| w == b"[1,2,3]"));
+    }
+
+    #[test]
+    fn test_raw() {
+        let fs = "test_data/typed_raw";
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<String, Vec<u8>, Raw> = TypedBlobStore::new(fs, 200, 2).unwrap();
+        ts.insert(&"k".to_string(), &vec![0, 255, 7]).unwrap();
+        assert_eq!(ts.get(&"k".to_string()).unwrap(), vec![0, 255, 7]);
+        let keys: Vec<String> = ts.keys().map(|r| r.unwrap()).collect();
+        assert_eq!(keys, vec!["k"]);
+        for k in ts.keys() {
+            assert!(ts.contains_key(&k.unwrap()).unwrap());
+        }
+    }
+
+    // An empty key would be written as a free section, so it is turned
+    // away before anything is written and the bucket still reads
+    #[test]
+    fn test_empty_key() {
+        let fs = "test_data/typed_empty_key";
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<String, String, Raw> = TypedBlobStore::new(fs, 200, 1).unwrap();
+        let (k, v) = ("k".to_string(), "v".to_string());
+        ts.insert(&k, &v).unwrap();
+        assert!(matches!(ts.insert(&String::new(), &v), Err(BlobError::EmptyKey)));
+        assert_eq!(ts.get(&k).unwrap(), v);
+        assert_eq!(ts.len(), 1);
+
+        drop(ts);
+        let ts: TypedBlobStore<String, String, Raw> = TypedBlobStore::open(fs).unwrap();
+        assert_eq!(ts.get(&k).unwrap(), v);
+        assert_eq!(ts.into_inner().verify_count().unwrap(), 1);
+
+        // bincode writes () as nothing too, through insert or a batch
+        let fs = "test_data/typed_empty_unit";
+        std::fs::remove_file(fs).ok();
+        let mut bs = BlobStore::new(fs, 200, 1).unwrap();
+        bs.insert(1, 1).unwrap();
+        assert!(matches!(bs.insert((), 1), Err(BlobError::EmptyKey)));
+        let mut wb = crate::batch::WriteBatch::new();
+        wb.insert(2, 2).unwrap();
+        wb.insert((), 1).unwrap();
+        assert!(matches!(bs.write_batch(wb), Err(BlobError::EmptyKey)));
+        assert_eq!(bs.verify_count().unwrap(), 1);
+        assert_eq!(bs.get(&1).unwrap().get_v::<i32>().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_wrong_codec() {
+        let fs = "test_data/typed_wrong";
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<u32, u32, Json> = TypedBlobStore::new(fs, 200, 2).unwrap();
+        ts.insert(&1, &1).unwrap();
+        drop(ts);
+
+        let r: Result<TypedBlobStore<u32, u32, MsgPack>, _> = TypedBlobStore::open(fs);
+        assert!(matches!(
+            r,
+            Err(BlobError::CodecMismatch {
+                found: 2,
+                expected: 3
+            })
+        ));
+        // the plain store would read it as bincode, so it will not
+        let mut bs = BlobStore::open(fs).unwrap();
+        assert!(matches!(bs.get(&1u32), Err(BlobError::CodecMismatch { .. })));
+        assert!(matches!(bs.insert(2u32, 2u32), Err(BlobError::CodecMismatch { .. })));
+        // iterating would decode as bincode too, so it stops at the first item
+        let all: Vec<_> = bs.iter::<u32, u32>().collect();
+        assert_eq!(all.len(), 1);
+        assert!(matches!(all[0], Err(BlobError::CodecMismatch { .. })));
+        assert!(matches!(
+            bs.keys::<u32>().next(),
+            Some(Err(BlobError::CodecMismatch { .. }))
+        ));
+        // the blobs themselves are fine to have, they are only bytes
+        assert!(bs.iter_blobs().all(|r| r.is_ok()));
+        // a plain store is bincode, so it opens typed as that
+        let fs2 = "test_data/typed_plain";
+        std::fs::remove_file(fs2).ok();
+        BlobStore::new(fs2, 200, 2).unwrap().insert(5u32, 6u32).unwrap();
+        let ts: TypedBlobStore<u32, u32> = TypedBlobStore::open(fs2).unwrap();
+        assert_eq!(ts.get(&5).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_blobmap_wrong_codec() {
+        let fs = "test_data/typed_blobmap_wrong";
+        std::fs::remove_file(fs).ok();
+        let mut ts: TypedBlobStore<u32, u32, Json> = TypedBlobStore::new(fs, 200, 2).unwrap();
+        ts.insert(&1, &1).unwrap();
+        drop(ts);
+
+        // BlobMap writes bincode too, so it will not take the file either way
+        assert!(matches!(BlobMap::open(fs), Err(BlobError::CodecMismatch { .. })));
+        assert!(matches!(
+            BlobMap::new(fs, 200, 2),
+            Err(BlobError::CodecMismatch { .. })
+        ));
+        let ts: TypedBlobStore<u32, u32, Json> = TypedBlobStore::open(fs).unwrap();
+        assert_eq!(ts.get(&1).unwrap(), 1);
+    }
+}
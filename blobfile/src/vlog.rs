@@ -0,0 +1,185 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+use memmap2::Mmap;
+
+use crate::error::BlobError;
+use crate::journal::write_at;
+
+/// Bytes an entry takes on top of its value, the length and the crc
+pub const ENTRY_EXTRA: u64 = 12;
+
+/// Each gc makes a new log, the generation in the store's header says which is in use
+pub fn vlog_name(fname: &str, gen: u64) -> String {
+    format!("{}.vlog.{}", fname, gen)
+}
+
+fn entry_crc(v: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(&(v.len() as u64).to_le_bytes());
+    h.update(v);
+    h.finalize()
+}
+
+fn corrupt(offset: u64, reason: &str) -> BlobError {
+    BlobError::Corrupt {
+        offset,
+        reason: format!("value log: {}", reason),
+    }
+}
+
+/// Values too big to keep in a bucket, one after another. Nothing in it is
+/// ever written over, so it needs no journal: a value only counts once a
+/// record in the store points at it, and one that is replaced or removed
+/// is just dead space until gc copies what is still used into a new log.
+///
+/// Each entry is the value's length, the value, then a crc32 of both.
+pub(crate) struct ValueLog {
+    file: File,
+    end: u64,
+    synced: u64,   // how much of it is known to be on disk
+    appended: u64, // bytes this handle put on the end, others may add more
+    map: Option<Mmap>,
+}
+
+impl ValueLog {
+    /// Starts an empty log, anything already there is thrown away
+    pub fn create(fname: &str) -> Result<Self, BlobError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .read(true)
+            .open(fname)?;
+        Ok(ValueLog {
+            file,
+            end: 0,
+            synced: 0,
+            appended: 0,
+            map: None,
+        })
+    }
+
+    pub fn open(fname: &str, read_only: bool) -> Result<Self, BlobError> {
+        let file = OpenOptions::new()
+            .write(!read_only)
+            .read(true)
+            .open(fname)?;
+        let end = file.metadata()?.len();
+        Ok(ValueLog {
+            file,
+            end,
+            synced: end,
+            appended: 0,
+            map: None,
+        })
+    }
+
+    /// Another handle on the same file, for a reader of the store
+    pub fn try_clone(&self) -> Result<Self, BlobError> {
+        Ok(ValueLog {
+            file: self.file.try_clone()?,
+            end: self.end,
+            synced: self.synced,
+            appended: 0,
+            map: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end
+    }
+
+    pub fn appended(&self) -> u64 {
+        self.appended
+    }
+
+    /// Adds v on the end, giving back where its entry starts
+    pub fn append(&mut self, v: &[u8], budget: &mut Option<u64>) -> Result<u64, BlobError> {
+        // a store BlobMap is moving into appends to this file too
+        let pos = self.end.max(self.file.metadata()?.len());
+        self.write_entry(pos, v, budget)?;
+        Ok(pos)
+    }
+
+    /// Makes the log long enough for a len long value on the end, giving
+    /// back where its entry goes. Handles appending side by side each
+    /// reserve in turn, then write their entries at the same time.
+    pub fn reserve(&mut self, len: u64) -> Result<u64, BlobError> {
+        let pos = self.end.max(self.file.metadata()?.len());
+        self.end = pos + len + ENTRY_EXTRA;
+        self.file.set_len(self.end)?;
+        Ok(pos)
+    }
+
+    /// Writes v's entry at pos, the end of the log or room reserved for it
+    pub fn write_entry(
+        &mut self,
+        pos: u64,
+        v: &[u8],
+        budget: &mut Option<u64>,
+    ) -> Result<(), BlobError> {
+        let mut buf = Vec::with_capacity(v.len() + ENTRY_EXTRA as usize);
+        buf.extend_from_slice(&(v.len() as u64).to_le_bytes());
+        buf.extend_from_slice(v);
+        buf.extend_from_slice(&entry_crc(v).to_le_bytes());
+        // whatever happens it is past the end now, a retry must not land on half of it
+        self.end = self.end.max(pos + buf.len() as u64);
+        self.appended += buf.len() as u64;
+        write_at(&self.file, pos, &buf, budget)
+    }
+
+    /// Picks up what other handles on the file have appended
+    pub fn catch_up(&mut self) -> Result<(), BlobError> {
+        self.end = self.end.max(self.file.metadata()?.len());
+        Ok(())
+    }
+
+    /// Syncs whatever was appended since the last time, if anything was
+    pub fn sync(&mut self) -> Result<(), BlobError> {
+        if self.synced < self.end {
+            self.file.sync_data()?;
+            self.synced = self.end;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn synced(&self) -> u64 {
+        self.synced
+    }
+
+    // the value out of a whole entry, if it is the len long one it should be
+    fn check(pos: u64, len: u64, ent: &[u8]) -> Result<&[u8], BlobError> {
+        if u64::from_le_bytes(ent[..8].try_into().unwrap()) != len {
+            return Err(corrupt(pos, "entry is not the length its record says"));
+        }
+        let (v, crc) = ent[8..].split_at(len as usize);
+        if u32::from_le_bytes(crc.try_into().unwrap()) != entry_crc(v) {
+            return Err(corrupt(pos, "entry checksum does not match"));
+        }
+        Ok(v)
+    }
+
+    /// The len long value of the entry at pos
+    pub fn read(&self, pos: u64, len: u64) -> Result<Vec<u8>, BlobError> {
+        let mut ent = vec![0u8; (len + ENTRY_EXTRA) as usize];
+        self.file.read_exact_at(&mut ent, pos)?;
+        Ok(Self::check(pos, len, &ent)?.to_vec())
+    }
+
+    /// Same as read but straight out of a map of the log, no copy made.
+    /// The map is redone when the entry is past its end.
+    pub fn slice(&mut self, pos: u64, len: u64) -> Result<&[u8], BlobError> {
+        let end = pos + len + ENTRY_EXTRA;
+        if self.map.as_ref().is_none_or(|m| (m.len() as u64) < end) {
+            // safe as long as nobody cuts the log short, and only gc drops one
+            self.map = Some(unsafe { Mmap::map(&self.file)? });
+        }
+        let m = self.map.as_ref().unwrap();
+        if (m.len() as u64) < end {
+            return Err(corrupt(pos, "entry runs past the end of the log"));
+        }
+        Self::check(pos, len, &m[pos as usize..end as usize])
+    }
+}
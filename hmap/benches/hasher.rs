@@ -0,0 +1,35 @@
+// MHash against std's SipHash on the two kinds of key HMap sees most
+// run with: cargo bench -p hmap --bench hasher
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use hmap::MHashBuilder;
+
+fn bench_hash(c: &mut Criterion) {
+    let words: Vec<String> = (0..1000).map(|x| format!("user_{:08}@example.com", x)).collect();
+    let m = MHashBuilder::with_seed(55);
+    let sip = RandomState::new();
+
+    let mut group = c.benchmark_group("hash_u64");
+    group.bench_function("mhash", |b| {
+        b.iter(|| (0..1000u64).fold(0, |a, x| a ^ m.hash_one(black_box(x))))
+    });
+    group.bench_function("siphash", |b| {
+        b.iter(|| (0..1000u64).fold(0, |a, x| a ^ sip.hash_one(black_box(x))))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("hash_str");
+    group.bench_function("mhash", |b| {
+        b.iter(|| words.iter().fold(0, |a, w| a ^ m.hash_one(black_box(w))))
+    });
+    group.bench_function("siphash", |b| {
+        b.iter(|| words.iter().fold(0, |a, w| a ^ sip.hash_one(black_box(w))))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_hash);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+// Chained buckets (HMap) against the flat open addressing table (OpenHMap)
+// run with: cargo bench -p hmap
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use hmap::{HMap, OpenHMap};
+
+const SIZES: [u64; 2] = [10_000, 1_000_000];
+
+fn bench_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+    for n in SIZES {
+        group.bench_with_input(BenchmarkId::new("chained", n), &n, |b, &n| {
+            b.iter(|| {
+                let mut hm = HMap::new();
+                for x in 0..n {
+                    hm.insert(x, x);
+                }
+                hm
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("open", n), &n, |b, &n| {
+            b.iter(|| {
+                let mut hm = OpenHMap::new();
+                for x in 0..n {
+                    hm.insert(x, x);
+                }
+                hm
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    group.sample_size(10);
+    for n in SIZES {
+        let mut chained = HMap::new();
+        let mut open = OpenHMap::new();
+        for x in 0..n {
+            chained.insert(x, x);
+            open.insert(x, x);
+        }
+        group.bench_with_input(BenchmarkId::new("chained", n), &n, |b, &n| {
+            b.iter(|| {
+                for x in 0..n {
+                    black_box(chained.get(&x));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("open", n), &n, |b, &n| {
+            b.iter(|| {
+                for x in 0..n {
+                    black_box(open.get(&x));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_insert, bench_get);
+criterion_main!(benches);
@@ -0,0 +1,254 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{HMap, MHashBuilder};
+
+const SHARDS: usize = 16;
+
+/// HMap split into shards, each behind its own RwLock,
+/// so threads working on different shards never wait on each other.
+/// A shard is a whole HMap, so a grow only ever locks the one shard
+/// it happens in, and it is still moved a bucket at a time.
+///
+/// Values are handed out as clones (or through a closure)
+/// as a reference can not outlive the shard lock.
+pub struct ConcurrentHMap<K, V, S = MHashBuilder> {
+    shards: Vec<RwLock<HMap<K, V, S>>>,
+    hasher: S,
+}
+
+impl<K: Hash + Eq, V> ConcurrentHMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(SHARDS)
+    }
+
+    pub fn with_shards(n: usize) -> Self {
+        Self::with_shards_and_hasher(n, MHashBuilder::new())
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ConcurrentHMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> ConcurrentHMap<K, V, S> {
+    pub fn with_shards_and_hasher(n: usize, hasher: S) -> Self {
+        ConcurrentHMap {
+            shards: (0..n.max(1))
+                .map(|_| RwLock::new(HMap::with_hasher(hasher.clone())))
+                .collect(),
+            hasher,
+        }
+    }
+
+    // HMap picks buckets with the low bits of the hash, so use the high ones here,
+    // otherwise every key in a shard would land in the same few buckets
+    fn shard<Q: Hash + ?Sized>(&self, k: &Q) -> &RwLock<HMap<K, V, S>> {
+        let h = self.hasher.hash_one(k);
+        &self.shards[((h >> 32) as usize) % self.shards.len()]
+    }
+
+    // A panic while a shard was locked may have come from K's hash or eq
+    // half way through moving a bucket, leaving pairs out of the map and
+    // len wrong, so a poisoned shard is not used again: the panic is passed on
+    fn read<Q: Hash + ?Sized>(&self, k: &Q) -> RwLockReadGuard<'_, HMap<K, V, S>> {
+        self.shard(k).read().unwrap()
+    }
+
+    fn write<Q: Hash + ?Sized>(&self, k: &Q) -> RwLockWriteGuard<'_, HMap<K, V, S>> {
+        self.shard(k).write().unwrap()
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.read(k).get(k).cloned()
+    }
+
+    /// Runs f on the value while the shard is read locked
+    pub fn get_with<Q, R, F>(&self, k: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&V) -> R,
+    {
+        self.read(k).get(k).map(f)
+    }
+
+    pub fn contains_key<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.read(k).contains_key(k)
+    }
+
+    /// Returns the old value if the key was already in the map
+    pub fn insert(&self, k: K, v: V) -> Option<V> {
+        self.write(&k).insert(k, v)
+    }
+
+    pub fn remove<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.write(k).remove(k)
+    }
+
+    /// Changes the value in place, false if the key was not there
+    pub fn update<Q, F>(&self, k: &Q, f: F) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&mut V),
+    {
+        match self.write(k).get_mut(k) {
+            Some(v) => {
+                f(v);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes the value in place, or puts v in if the key was not there,
+    /// all under one lock so no other thread can get in between
+    pub fn update_or_insert<F>(&self, k: K, f: F, v: V)
+    where
+        F: FnOnce(&mut V),
+    {
+        self.write(&k).entry(k).and_modify(f).or_insert(v);
+    }
+
+    /// Locks each shard in turn, so with other threads writing
+    /// this is only a snapshot of each shard, not of the whole map
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the whole thing back out, the borrow checker makes sure no one else has it
+    pub fn into_inner(self) -> Vec<HMap<K, V, S>> {
+        self.shards
+            .into_iter()
+            .map(|s| s.into_inner().unwrap())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_single_thread() {
+        let m = ConcurrentHMap::new();
+        assert_eq!(m.insert("a", 1), None);
+        assert_eq!(m.insert("a", 2), Some(1));
+        assert_eq!(m.get(&"a"), Some(2));
+        assert!(m.update(&"a", |v| *v += 1));
+        assert!(!m.update(&"b", |v| *v += 1));
+        m.update_or_insert("b", |v| *v += 1, 10);
+        m.update_or_insert("b", |v| *v += 1, 10);
+        assert_eq!(m.get_with(&"b", |v| v * 2), Some(22));
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.remove(&"a"), Some(3));
+        assert!(!m.contains_key(&"a"));
+    }
+
+    // equal keys of 1 panic when compared, as a broken Eq might
+    struct Bomb(i32);
+
+    impl std::hash::Hash for Bomb {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            self.0.hash(state);
+        }
+    }
+
+    impl PartialEq for Bomb {
+        fn eq(&self, other: &Self) -> bool {
+            assert!(self.0 != 1 || other.0 != 1, "bad eq");
+            self.0 == other.0
+        }
+    }
+    impl Eq for Bomb {}
+
+    #[test]
+    fn test_poisoned_shard() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let m = ConcurrentHMap::with_shards(1);
+        m.insert(Bomb(1), 1);
+        assert!(catch_unwind(AssertUnwindSafe(|| m.insert(Bomb(1), 2))).is_err());
+        // the panic was inside the map, so it can not be trusted any more
+        assert!(catch_unwind(AssertUnwindSafe(|| m.get(&Bomb(2)))).is_err());
+        assert!(catch_unwind(AssertUnwindSafe(|| m.len())).is_err());
+    }
+
+    // Every thread owns its own slice of keys and checks its own view is
+    // always right, while all of them share the counters and keep the shards
+    // growing (and shrinking) the whole time.
+    #[test]
+    fn test_stress() {
+        const THREADS: u64 = 8;
+        const PER: u64 = 20_000;
+        let m: ConcurrentHMap<u64, u64> = ConcurrentHMap::with_shards(4);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            // a reader that keeps checking values it finds are ones that could be there
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    for k in (0..THREADS * PER).step_by(997) {
+                        if let Some(v) = m.get(&k) {
+                            assert!(v == k || v == k + 1, "{} -> {}", k, v);
+                        }
+                    }
+                }
+            });
+            let writers: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let m = &m;
+                    s.spawn(move || {
+                        let keys = t * PER..(t + 1) * PER;
+                        for k in keys.clone() {
+                            assert_eq!(m.insert(k, k), None);
+                            m.update_or_insert(u64::MAX - k % 10, |c| *c += 1, 1);
+                        }
+                        for k in keys.clone() {
+                            assert!(m.update(&k, |v| *v += 1));
+                            assert_eq!(m.get(&k), Some(k + 1));
+                        }
+                        // drop most of them again so shards shrink while others grow
+                        for k in keys.clone().filter(|k| k % 4 != 0) {
+                            assert_eq!(m.remove(&k), Some(k + 1));
+                        }
+                    })
+                })
+                .collect();
+            for w in writers {
+                w.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(m.len() as u64, THREADS * PER / 4 + 10);
+        for k in 0..THREADS * PER {
+            let want = if k % 4 == 0 { Some(k + 1) } else { None };
+            assert_eq!(m.get(&k), want);
+        }
+        let total: u64 = (0..10).map(|c| m.get(&(u64::MAX - c)).unwrap()).sum();
+        assert_eq!(total, THREADS * PER);
+    }
+}
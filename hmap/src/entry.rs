@@ -0,0 +1,143 @@
+use std::hash::{BuildHasher, Hash};
+
+use crate::{BucketList, HMap, MHashBuilder};
+
+pub enum Entry<'a, K, V, S = MHashBuilder> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> Entry<'a, K, V, S> {
+    pub fn or_insert(self, v: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(v),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(f()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(e) = &mut self {
+            f(e.get_mut());
+        }
+        self
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+}
+
+/// Points straight at the slot the pair sits in,
+/// `in_grow` says which of the two bucket lists that is
+pub struct OccupiedEntry<'a, K, V, S = MHashBuilder> {
+    map: &'a mut HMap<K, V, S>,
+    in_grow: bool,
+    bucket: usize,
+    idx: usize,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> OccupiedEntry<'a, K, V, S> {
+    pub(crate) fn new(map: &'a mut HMap<K, V, S>, in_grow: bool, bucket: usize, idx: usize) -> Self {
+        OccupiedEntry {
+            map,
+            in_grow,
+            bucket,
+            idx,
+        }
+    }
+
+    fn list(&self) -> &BucketList<K, V> {
+        if self.in_grow {
+            &self.map.grow
+        } else {
+            &self.map.main
+        }
+    }
+
+    fn list_mut(&mut self) -> &mut BucketList<K, V> {
+        if self.in_grow {
+            &mut self.map.grow
+        } else {
+            &mut self.map.main
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.list().buckets[self.bucket][self.idx].0
+    }
+
+    pub fn get(&self) -> &V {
+        &self.list().buckets[self.bucket][self.idx].1
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        let (b, i) = (self.bucket, self.idx);
+        &mut self.list_mut().buckets[b][i].1
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        let list = if self.in_grow {
+            &mut self.map.grow
+        } else {
+            &mut self.map.main
+        };
+        &mut list.buckets[self.bucket][self.idx].1
+    }
+
+    pub fn insert(&mut self, v: V) -> V {
+        std::mem::replace(self.get_mut(), v)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    pub fn remove_entry(mut self) -> (K, V) {
+        let (b, i) = (self.bucket, self.idx);
+        let res = self.list_mut().take(b, i);
+        self.map.after_remove();
+        res
+    }
+}
+
+/// Keeps the hash worked out in `HMap::entry` so insert does not redo it
+pub struct VacantEntry<'a, K, V, S = MHashBuilder> {
+    map: &'a mut HMap<K, V, S>,
+    hash: u64,
+    key: K,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> VacantEntry<'a, K, V, S> {
+    pub(crate) fn new(map: &'a mut HMap<K, V, S>, hash: u64, key: K) -> Self {
+        VacantEntry { map, hash, key }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, v: V) -> &'a mut V {
+        self.map.insert_new(self.hash, self.key, v)
+    }
+}
@@ -1,29 +1,136 @@
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasher, Hash, Hasher};
 
+// 2^64 / golden ratio, odd so multiplying by it can be undone
+const K: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// Takes input a word (8 bytes) at a time.
+/// Every step is a multiply by K then an xorshift, both can be undone,
+/// so two inputs of the same length that differ in only one word
+/// can never collide. `finish` then runs the splitmix64 finaliser
+/// so each input bit reaches every output bit.
+#[derive(Debug, Clone)]
 pub struct MHash {
-    prev: u8,
-    n: u128,
+    state: u64,
+    len: u64, // bytes fed in, so [1] and [1, 0] end up different
+}
+
+impl MHash {
+    pub fn with_seed(seed: u64) -> Self {
+        MHash {
+            state: seed,
+            len: 0,
+        }
+    }
+
+    fn add(&mut self, w: u64) {
+        let x = (self.state ^ w).wrapping_mul(K);
+        // the multiply only carries upwards, fold the top half back down
+        // so the next word can not cancel out what this one changed
+        self.state = x ^ (x >> 32);
+    }
+}
+
+impl Default for MHash {
+    fn default() -> Self {
+        Self::with_seed(0)
+    }
 }
 
 impl Hasher for MHash {
     fn write(&mut self, dt: &[u8]) {
-        for d in dt {
-            self.n = ((self.n + 11) * (*d as u128 + 13) + ((d ^ self.prev) as u128))
-                % (std::u64::MAX as u128);
-            self.prev = *d;
+        self.len += dt.len() as u64;
+        let mut words = dt.chunks_exact(8);
+        for w in &mut words {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(w);
+            self.add(u64::from_le_bytes(b));
+        }
+        let rest = words.remainder();
+        if !rest.is_empty() {
+            let mut b = [0u8; 8];
+            b[..rest.len()].copy_from_slice(rest);
+            self.add(u64::from_le_bytes(b));
         }
     }
 
+    // the fixed size writes skip the byte slice handling completely
+
+    fn write_u8(&mut self, i: u8) {
+        self.len += 1;
+        self.add(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.len += 2;
+        self.add(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.len += 4;
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.len += 8;
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
     fn finish(&self) -> u64 {
-        self.n as u64
+        let mut z = self.state ^ self.len.wrapping_mul(K);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// The default BuildHasher for HMap, every MHash it builds
+/// starts from the same seed.
+///
+/// This hasher does not resist hash flooding (DoS by keys picked to
+/// collide). The seed is only the starting state and every word then goes
+/// through the same fixed steps that can all be undone, so a random seed
+/// does not keep someone who picks the keys from filling one bucket.
+/// For keys from untrusted input use std's RandomState (SipHash) with
+/// `HMap::with_hasher`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MHashBuilder {
+    seed: u64,
+}
+
+impl MHashBuilder {
+    /// Random seed, so iteration order differs from run to run.
+    /// This is not protection against chosen keys, see above.
+    pub fn new() -> Self {
+        MHashBuilder {
+            seed: rand::random(),
+        }
+    }
+
+    /// Fixed seed, for when the layout needs to be the same every time (tests)
+    pub fn with_seed(seed: u64) -> Self {
+        MHashBuilder { seed }
+    }
+}
+
+impl Default for MHashBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BuildHasher for MHashBuilder {
+    type Hasher = MHash;
+    fn build_hasher(&self) -> MHash {
+        MHash::with_seed(self.seed)
     }
 }
 
 pub fn hash<T: Hash>(seed: u64, t: T) -> u64 {
-    let mut h = MHash { n: 0, prev: 0 };
-    h.write_u64(seed);
-    t.hash(&mut h);
-    h.finish()
+    MHashBuilder::with_seed(seed).hash_one(t)
 }
 
 #[cfg(test)]
@@ -45,4 +152,123 @@ mod test {
             prev = curr;
         }
     }
+
+    #[test]
+    pub fn test_builder_seed() {
+        let a = MHashBuilder::with_seed(55);
+        assert_eq!(a.hash_one("cat"), hash(55, "cat"));
+        assert_eq!(a.hash_one("cat"), MHashBuilder::with_seed(55).hash_one("cat"));
+        assert!(a.hash_one("cat") != MHashBuilder::with_seed(56).hash_one("cat"));
+    }
+
+    #[test]
+    pub fn test_word_and_bytes_differ_by_len() {
+        let b = MHashBuilder::with_seed(3);
+        let h = |d: &[u8]| {
+            let mut m = b.build_hasher();
+            m.write(d);
+            m.finish()
+        };
+        assert!(h(&[1]) != h(&[1, 0]));
+        assert!(h(&[]) != h(&[0]));
+        assert!(h(&[1, 2, 3, 4, 5, 6, 7, 8, 9]) != h(&[1, 2, 3, 4, 5, 6, 7, 8]));
+    }
+
+    // The limits below are picked so a uniform hash
+    // fails them less than once in a very long while.
+
+    // Flipping any one input bit should flip each output bit half the time
+    fn avalanche<F: Fn(u64) -> u64>(f: F) {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        const ROUNDS: usize = 2000;
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut flips = [[0u32; 64]; 64];
+        for _ in 0..ROUNDS {
+            let x: u64 = rng.gen();
+            let base = f(x);
+            for (i, row) in flips.iter_mut().enumerate() {
+                let diff = base ^ f(x ^ (1 << i));
+                for (o, n) in row.iter_mut().enumerate() {
+                    *n += ((diff >> o) & 1) as u32;
+                }
+            }
+        }
+        for (i, row) in flips.iter().enumerate() {
+            for (o, n) in row.iter().enumerate() {
+                let p = *n as f64 / ROUNDS as f64;
+                assert!(
+                    (0.4..0.6).contains(&p),
+                    "input bit {} flips output bit {} with p = {}",
+                    i,
+                    o,
+                    p
+                );
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_avalanche_u64() {
+        avalanche(|x| hash(55, x));
+    }
+
+    #[test]
+    pub fn test_avalanche_bytes() {
+        // a 12 byte key, so both the full word and the tail path are hit
+        avalanche(|x| {
+            let mut d = [7u8; 12];
+            d[2..10].copy_from_slice(&x.to_le_bytes());
+            hash(55, &d[..])
+        });
+    }
+
+    // Pearson chi squared of keys spread over n buckets the way BucketList
+    // picks them. For uniform hashing it has mean n - 1 and
+    // standard deviation sqrt(2(n - 1)), allow 6 of those.
+    fn chi_squared<T: Hash, I: Iterator<Item = T>>(keys: I, n: usize) {
+        let mut counts = vec![0f64; n];
+        let mut total = 0f64;
+        for k in keys {
+            counts[(hash(55, k) as usize) % n] += 1.0;
+            total += 1.0;
+        }
+        let want = total / n as f64;
+        let chi: f64 = counts.iter().map(|c| (c - want) * (c - want) / want).sum();
+        let dof = (n - 1) as f64;
+        let limit = dof + 6.0 * (2.0 * dof).sqrt();
+        assert!(chi < limit, "chi squared {} over {} buckets", chi, n);
+    }
+
+    #[test]
+    pub fn test_buckets_sequential_ints() {
+        chi_squared(0..100_000u64, 1024);
+        chi_squared(0..100_000u64, 1000);
+        // multiples of the bucket count are a classic way to break modulo
+        chi_squared((0..100_000u64).map(|x| x * 1024), 1024);
+    }
+
+    #[test]
+    pub fn test_buckets_similar_strings() {
+        chi_squared((0..100_000).map(|x| format!("key{}", x)), 1024);
+        chi_squared((0..100_000).map(|x| format!("user_{:08}@example.com", x)), 977);
+    }
+
+    fn collisions<I: Iterator<Item = u64>>(hashes: I) -> usize {
+        let mut v: Vec<u64> = hashes.collect();
+        v.sort_unstable();
+        v.windows(2).filter(|w| w[0] == w[1]).count()
+    }
+
+    #[test]
+    pub fn test_collisions() {
+        // one word inputs go through steps that can all be undone, so no collisions at all
+        assert_eq!(collisions((0..1_000_000u64).map(|x| hash(55, x))), 0);
+        assert_eq!(
+            collisions((0..200_000).map(|x| hash(55, format!("key{}", x)))),
+            0
+        );
+        // in 32 bits 200k keys should give about n^2 / 2^33 = 4.7 collisions
+        let low = collisions((0..200_000).map(|x| hash(55, format!("key{}", x)) & 0xFFFF_FFFF));
+        assert!(low < 20, "{} collisions in the low 32 bits", low);
+    }
 }
@@ -0,0 +1,233 @@
+use std::iter::{Chain, Flatten};
+use std::marker::PhantomData;
+
+use crate::{BucketList, HMap};
+
+// Every pair is in exactly one of main or grow, even while a move is
+// going on, so walking main then grow sees each pair once.
+// `left` is only there to give an exact size_hint.
+
+type Buckets<'a, K, V> = Flatten<std::slice::Iter<'a, Vec<(K, V)>>>;
+type BucketsMut<'a, K, V> = Flatten<std::slice::IterMut<'a, Vec<(K, V)>>>;
+type OwnedBuckets<K, V> = Flatten<std::vec::IntoIter<Vec<(K, V)>>>;
+
+pub struct Iter<'a, K, V> {
+    inner: Chain<Buckets<'a, K, V>, Buckets<'a, K, V>>,
+    left: usize,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    pub(crate) fn new(main: &'a BucketList<K, V>, grow: &'a BucketList<K, V>) -> Self {
+        Iter {
+            inner: main
+                .buckets
+                .iter()
+                .flatten()
+                .chain(grow.buckets.iter().flatten()),
+            left: main.len + grow.len,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.inner.next()?;
+        self.left -= 1;
+        Some((k, v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl<K, V> ExactSizeIterator for Iter<'_, K, V> {}
+
+pub struct IterMut<'a, K, V> {
+    inner: Chain<BucketsMut<'a, K, V>, BucketsMut<'a, K, V>>,
+    left: usize,
+}
+
+impl<'a, K, V> IterMut<'a, K, V> {
+    pub(crate) fn new(main: &'a mut BucketList<K, V>, grow: &'a mut BucketList<K, V>) -> Self {
+        IterMut {
+            left: main.len + grow.len,
+            inner: main
+                .buckets
+                .iter_mut()
+                .flatten()
+                .chain(grow.buckets.iter_mut().flatten()),
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.inner.next()?;
+        self.left -= 1;
+        Some((&*k, v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IterMut<'_, K, V> {}
+
+pub struct IntoIter<K, V> {
+    inner: Chain<OwnedBuckets<K, V>, OwnedBuckets<K, V>>,
+    left: usize,
+}
+
+impl<K, V> IntoIter<K, V> {
+    pub(crate) fn new(main: BucketList<K, V>, grow: BucketList<K, V>) -> Self {
+        IntoIter {
+            left: main.len + grow.len,
+            inner: main
+                .buckets
+                .into_iter()
+                .flatten()
+                .chain(grow.buckets.into_iter().flatten()),
+        }
+    }
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let res = self.inner.next()?;
+        self.left -= 1;
+        Some(res)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
+
+/// Owns the pairs taken out of the map, the map is already empty
+/// by the time this is handed out.
+pub struct Drain<'a, K, V> {
+    inner: IntoIter<K, V>,
+    _map: PhantomData<&'a mut BucketList<K, V>>,
+}
+
+impl<K, V> Drain<'_, K, V> {
+    pub(crate) fn new(main: BucketList<K, V>, grow: BucketList<K, V>) -> Self {
+        Drain {
+            inner: IntoIter::new(main, grow),
+            _map: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Iterator for Drain<'_, K, V> {
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for Drain<'_, K, V> {}
+
+pub struct Keys<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Keys<'a, K, V> {
+    pub(crate) fn new(inner: Iter<'a, K, V>) -> Self {
+        Keys { inner }
+    }
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for Keys<'_, K, V> {}
+
+pub struct Values<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K, V> Values<'a, K, V> {
+    pub(crate) fn new(inner: Iter<'a, K, V>) -> Self {
+        Values { inner }
+    }
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for Values<'_, K, V> {}
+
+pub struct ValuesMut<'a, K, V> {
+    inner: IterMut<'a, K, V>,
+}
+
+impl<'a, K, V> ValuesMut<'a, K, V> {
+    pub(crate) fn new(inner: IterMut<'a, K, V>) -> Self {
+        ValuesMut { inner }
+    }
+}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> ExactSizeIterator for ValuesMut<'_, K, V> {}
+
+impl<K, V, S> IntoIterator for HMap<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter::new(self.main, self.grow)
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a HMap<K, V, S> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(&self.main, &self.grow)
+    }
+}
+
+impl<'a, K, V, S> IntoIterator for &'a mut HMap<K, V, S> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+    fn into_iter(self) -> Self::IntoIter {
+        IterMut::new(&mut self.main, &mut self.grow)
+    }
+}
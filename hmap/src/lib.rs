@@ -1,115 +1,340 @@
+mod concurrent;
+mod entry;
 mod hasher;
+mod iter;
+mod linked;
+mod open;
+mod set;
+mod traits;
 
-pub use hasher::hash; // 同时导出hash方法
-use std::{borrow::Borrow, hash::Hash};
+pub use concurrent::ConcurrentHMap;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use hasher::{hash, MHash, MHashBuilder}; // 同时导出hash方法
+pub use iter::{Drain, IntoIter, Iter, IterMut, Keys, Values, ValuesMut};
+pub use linked::{LinkedHMap, LinkedIter, LruCache};
+pub use open::{OpenEntry, OpenHMap, OpenOccupiedEntry, OpenVacantEntry};
+pub use set::{Difference, HSet, Intersection, SetIter, SymmetricDifference, Union};
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash},
+};
 
 const BSIZE: usize = 8;
-// const BGROW: usize = 8;
+const BGROW: usize = 2; // each grow makes this many times the buckets
 
-#[derive(Debug)]
+/// When HMap starts moving to a different number of buckets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HMapConfig {
+    /// grow as soon as an insert would make a bucket longer than this
+    pub max_bucket_len: usize,
+    /// grow when len / buckets would go over this
+    pub max_load: f64,
+    /// shrink once a remove takes len / buckets under this, 0.0 never shrinks
+    pub min_load: f64,
+    /// how many times more buckets each grow gives
+    pub grow_factor: usize,
+    /// most pairs one insert or remove moves from main to grow,
+    /// usize::MAX moves a whole bucket each time
+    pub move_budget: usize,
+}
+
+impl Default for HMapConfig {
+    fn default() -> Self {
+        HMapConfig {
+            max_bucket_len: BSIZE,
+            max_load: 1.0,
+            min_load: 0.25,
+            grow_factor: BGROW,
+            move_budget: usize::MAX,
+        }
+    }
+}
+
+impl HMapConfig {
+    /// Panics on a config the map can not work with: max_load has to be
+    /// above 0 and finite, min_load under it, and each grow has to grow
+    pub fn check(&self) {
+        assert!(
+            self.max_load > 0.0 && self.max_load.is_finite(),
+            "max_load must be above 0, got {}",
+            self.max_load
+        );
+        assert!(
+            self.min_load >= 0.0 && self.min_load < self.max_load,
+            "min_load must be from 0 up to max_load, got {}",
+            self.min_load
+        );
+        assert!(self.grow_factor >= 2, "grow_factor must be at least 2");
+        assert!(self.max_bucket_len > 0, "max_bucket_len must be above 0");
+        assert!(self.move_budget > 0, "move_budget must be above 0");
+    }
+
+    // fewest buckets (a power of two) that hold n without going over max_load
+    fn buckets_for(&self, n: usize) -> usize {
+        // the float to usize cast saturates, so too many comes out as None here
+        ((n as f64 / self.max_load).ceil() as usize)
+            .checked_next_power_of_two()
+            .expect("capacity overflow")
+    }
+}
+
+// Hashes come from the HMap's BuildHasher and are passed in,
+// so main and grow agree on them and a key is only hashed once per call
+#[derive(Debug, Clone)]
 pub struct BucketList<K, V> {
-    seed: u64,
     len: usize,
     buckets: Vec<Vec<(K, V)>>,
 }
 
-impl<K: Hash + Eq, V> BucketList<K, V> {
+impl<K: Eq, V> BucketList<K, V> {
     fn new() -> Self {
+        Self::with_buckets(1)
+    }
+
+    fn with_buckets(n: usize) -> Self {
         BucketList {
-            seed: rand::random(),
             len: 0,
-            buckets: vec![Vec::new()],
+            buckets: (0..n.max(1)).map(|_| Vec::new()).collect(),
         }
     }
 
-    // usize returned how big chosen bucket is
-    // tell caller if its too full
-    fn push(&mut self, k: K, v: V) -> usize {
-        let h = (hash(self.seed, &k) as usize) % self.buckets.len();
+    fn bucket_of(&self, h: u64) -> usize {
+        (h as usize) % self.buckets.len()
+    }
+
+    // returns where the pair landed, so the caller can hand out a reference to it
+    fn push(&mut self, h: u64, k: K, v: V) -> (usize, usize) {
+        let h = self.bucket_of(h);
         self.buckets[h].push((k, v));
         self.len += 1;
-        self.buckets[h].len()
+        (h, self.buckets[h].len() - 1)
     }
 
-    fn get<KB>(&self, k: &KB) -> Option<&V>
+    // (bucket, index in bucket) of the key if it is in this list
+    fn find<KB>(&self, h: u64, k: &KB) -> Option<(usize, usize)>
     where
         K: Borrow<KB>,
-        KB: Hash + Eq + ?Sized,
+        KB: Eq + ?Sized,
     {
-        let h = (hash(self.seed, &k) as usize) % self.buckets.len();
-        for (ik, iv) in &self.buckets[h] {
-            if k == (ik as &K).borrow() {
-                return Some(iv);
-            }
-        }
-        None
+        let h = self.bucket_of(h);
+        self.buckets[h]
+            .iter()
+            .position(|(ik, _)| k == ik.borrow())
+            .map(|i| (h, i))
     }
-    fn get_mut<KB>(&mut self, k: &KB) -> Option<&mut V>
+
+    fn get<KB>(&self, h: u64, k: &KB) -> Option<&V>
     where
         K: Borrow<KB>,
-        KB: Hash + Eq + ?Sized,
+        KB: Eq + ?Sized,
     {
-        let h = (hash(self.seed, &k) as usize) % self.buckets.len();
-        for (ik, iv) in &mut self.buckets[h] {
-            if k == (ik as &K).borrow() {
-                return Some(iv);
-            }
-        }
-        None
+        let (h, i) = self.find(h, k)?;
+        Some(&self.buckets[h][i].1)
     }
 
-    fn bucket(&mut self, n: usize) -> Option<Vec<(K, V)>> {
-        if n >= self.buckets.len() {
-            return None;
-        }
-        let mut res = Vec::new();
-        std::mem::swap(&mut res, &mut self.buckets[n]);
-        self.len -= res.len();
+    fn get_mut<KB>(&mut self, h: u64, k: &KB) -> Option<&mut V>
+    where
+        K: Borrow<KB>,
+        KB: Eq + ?Sized,
+    {
+        let (h, i) = self.find(h, k)?;
+        Some(&mut self.buckets[h][i].1)
+    }
+
+    // order inside a bucket does not matter, so swap_remove is fine
+    fn take(&mut self, h: usize, i: usize) -> (K, V) {
+        self.len -= 1;
+        self.buckets[h].swap_remove(i)
+    }
+
+    fn remove<KB>(&mut self, h: u64, k: &KB) -> Option<(K, V)>
+    where
+        K: Borrow<KB>,
+        KB: Eq + ?Sized,
+    {
+        let (h, i) = self.find(h, k)?;
+        Some(self.take(h, i))
+    }
+
+    fn pop(&mut self, n: usize) -> Option<(K, V)> {
+        let res = self.buckets[n].pop()?;
+        self.len -= 1;
         Some(res)
     }
 
+    // only called on an empty list, before a move into it starts
     fn set_buckets(&mut self, n: usize) {
+        self.buckets.truncate(n.max(1));
         for _ in self.buckets.len()..n {
             self.buckets.push(Vec::new());
         }
     }
 }
 
-#[derive(Debug)]
-pub struct HMap<K, V> {
-    n_moved: usize,         // 已经移动的元素数量
+/// S picks the hash function, MHash unless told otherwise.
+/// Use `with_hasher` to plug in anything else that is a BuildHasher,
+/// e.g. std's RandomState for SipHash.
+#[derive(Clone)]
+pub struct HMap<K, V, S = MHashBuilder> {
+    moving: bool,           // main is being moved into grow
+    n_moved: usize,         // main 里已经移动完的桶数量
     main: BucketList<K, V>, // 主要放置的数据
     grow: BucketList<K, V>, // 将要移动到的数据的地方
+    hasher: S,
+    config: HMapConfig,
+    min_buckets: usize, // never shrink below what with_capacity or reserve asked for
+    rehashes: usize,    // moves finished
+    pairs_moved: usize, // pairs moved by all of them
+}
+
+/// A look at how full an HMap is and how far along a move is
+#[derive(Debug, Clone, PartialEq)]
+pub struct HMapStats {
+    pub len: usize,
+    pub capacity: usize,
+    /// buckets in the list being filled, grow if a move is going on
+    pub bucket_count: usize,
+    /// share of main's buckets already moved, None if no move is going on
+    pub migration_progress: Option<f64>,
+    pub max_chain: usize,
+    /// average over the buckets that have anything in them
+    pub avg_chain: f64,
+    /// moves finished since the map was made
+    pub rehashes: usize,
+    /// pairs moved from main to grow since the map was made
+    pub pairs_moved: usize,
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default> Default for HMap<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
 }
 
 impl<K: Hash + Eq, V> HMap<K, V> {
     pub fn new() -> Self {
+        Self::with_hasher(MHashBuilder::new())
+    }
+
+    /// Room for n pairs before the first grow
+    pub fn with_capacity(n: usize) -> Self {
+        Self::with_capacity_and_hasher(n, MHashBuilder::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> HMap<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    pub fn with_capacity_and_hasher(n: usize, hasher: S) -> Self {
+        let config = HMapConfig::default();
+        let min_buckets = config.buckets_for(n);
         HMap {
+            moving: false,
             n_moved: 0,
-            main: BucketList::new(),
+            main: BucketList::with_buckets(min_buckets),
             grow: BucketList::new(),
+            hasher,
+            config,
+            min_buckets,
+            rehashes: 0,
+            pairs_moved: 0,
         }
     }
 
-    pub fn insert(&mut self, k: K, v: V) {
-        if let Some(iv) = self.main.get_mut(&k) {
-            *iv = v;
-            return;
+    pub fn config(&self) -> &HMapConfig {
+        &self.config
+    }
+
+    /// Takes effect from the next insert or remove.
+    /// Panics if the config does not pass `HMapConfig::check`.
+    pub fn set_config(&mut self, config: HMapConfig) {
+        config.check();
+        self.config = config;
+    }
+
+    /// How many pairs fit before the next grow, going by max_load.
+    /// Mid move that is the list being moved into, smaller after a shrink.
+    pub fn capacity(&self) -> usize {
+        let buckets = if self.moving {
+            self.grow.buckets.len()
+        } else {
+            self.main.buckets.len()
+        };
+        (buckets as f64 * self.config.max_load) as usize
+    }
+
+    /// Makes sure `additional` more pairs fit without a grow.
+    /// Unlike the automatic grow this moves everything straight away.
+    /// Panics with "capacity overflow" if that many buckets can not be had.
+    pub fn reserve(&mut self, additional: usize) {
+        let n = self
+            .len()
+            .checked_add(additional)
+            .expect("capacity overflow");
+        let want = self.config.buckets_for(n);
+        self.min_buckets = self.min_buckets.max(want);
+        self.finish_move();
+        if want > self.main.buckets.len() {
+            self.start_move(want);
+            self.finish_move();
         }
-        if let Some(iv) = self.grow.get_mut(&k) {
-            *iv = v;
-            return;
+    }
+
+    /// Moves everything into as few buckets as hold the current pairs,
+    /// and forgets any capacity asked for before
+    pub fn shrink_to_fit(&mut self) {
+        self.min_buckets = 1;
+        self.finish_move();
+        let want = self.config.buckets_for(self.len());
+        if want < self.main.buckets.len() {
+            self.start_move(want);
+            self.finish_move();
         }
-        if self.n_moved > 0 {
-            // we have started move to bigger bucket list
-            self.grow.push(k, v);
-            self.move_bucket();
-            return;
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    fn hash<KR: Hash + ?Sized>(&self, kr: &KR) -> u64 {
+        self.hasher.hash_one(kr)
+    }
+
+    /// Returns the old value if the key was already in the map
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let h = self.hash(&k);
+        if let Some(iv) = self.main.get_mut(h, &k) {
+            return Some(std::mem::replace(iv, v));
         }
-        if self.main.push(k, v) > BSIZE / 2 {
+        if let Some(iv) = self.grow.get_mut(h, &k) {
+            return Some(std::mem::replace(iv, v));
+        }
+        self.insert_new(h, k, v);
+        None
+    }
+
+    // Caller guarantees k is not in the map yet.
+    // The migration step happens before the push so the new pair
+    // stays where it was put and we can return a reference to it.
+    fn insert_new(&mut self, h: u64, k: K, v: V) -> &mut V {
+        if self.moving {
+            // we have started move to bigger bucket list
+            self.move_next();
+        } else if self.main.buckets[self.main.bucket_of(h)].len() >= self.config.max_bucket_len
+            || (self.main.len + 1) as f64 > self.main.buckets.len() as f64 * self.config.max_load
+        {
             // grow buckets
             self.move_bucket();
         }
+        let list = if self.moving {
+            &mut self.grow
+        } else {
+            &mut self.main
+        };
+        let (b, i) = list.push(h, k, v);
+        &mut list.buckets[b][i].1
     }
 
     pub fn get<KR>(&self, kr: &KR) -> Option<&V>
@@ -117,41 +342,230 @@ impl<K: Hash + Eq, V> HMap<K, V> {
         K: Borrow<KR>,
         KR: Hash + Eq + ?Sized,
     {
-        self.main.get(kr).or_else(|| self.grow.get(kr))
+        let h = self.hash(kr);
+        self.main.get(h, kr).or_else(|| self.grow.get(h, kr))
     }
     pub fn get_mut<KR>(&mut self, kr: &KR) -> Option<&mut V>
     where
         K: Borrow<KR>,
         KR: Hash + Eq + ?Sized,
     {
-        self.main.get_mut(kr).or_else(|| self.grow.get_mut(kr))
+        let h = self.hash(kr);
+        self.main.get_mut(h, kr).or_else(|| self.grow.get_mut(h, kr))
         // 等价于下面的写法
-        // if let Some(b) = self.main.get_mut(kr) {
+        // if let Some(b) = self.main.get_mut(h, kr) {
         //     return Some(b);
         // }
 
-        // self.grow.get_mut(kr)
+        // self.grow.get_mut(h, kr)
+    }
+
+    pub fn contains_key<KR>(&self, kr: &KR) -> bool
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        self.get(kr).is_some()
+    }
+
+    // a key only ever lives in one of main or grow, even mid move,
+    // so removing from whichever has it is enough
+    pub fn remove<KR>(&mut self, kr: &KR) -> Option<V>
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(kr).map(|(_, v)| v)
+    }
+
+    pub fn remove_entry<KR>(&mut self, kr: &KR) -> Option<(K, V)>
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        let h = self.hash(kr);
+        let res = self.main.remove(h, kr).or_else(|| self.grow.remove(h, kr));
+        if res.is_some() {
+            self.after_remove();
+        }
+        res
+    }
+
+    // removes keep a move going just like inserts do,
+    // and start a shrink once the buckets are mostly empty
+    pub(crate) fn after_remove(&mut self) {
+        if self.moving {
+            self.move_next();
+            return;
+        }
+        let buckets = self.main.buckets.len();
+        if buckets > self.min_buckets
+            && (self.main.len as f64) < buckets as f64 * self.config.min_load
+        {
+            // leave room to grow again before the next move is needed
+            let want = self
+                .config
+                .buckets_for(self.main.len * 2)
+                .max(self.min_buckets);
+            if want < buckets {
+                self.start_move(want);
+            }
+        }
+    }
+
+    pub fn entry(&mut self, k: K) -> Entry<'_, K, V, S> {
+        let h = self.hash(&k);
+        if let Some((b, i)) = self.main.find(h, &k) {
+            return Entry::Occupied(OccupiedEntry::new(self, false, b, i));
+        }
+        if let Some((b, i)) = self.grow.find(h, &k) {
+            return Entry::Occupied(OccupiedEntry::new(self, true, b, i));
+        }
+        Entry::Vacant(VacantEntry::new(self, h, k))
     }
 
     pub fn len(&self) -> usize {
         self.main.len + self.grow.len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.drain();
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.main, &self.grow)
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut::new(&mut self.main, &mut self.grow)
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys::new(self.iter())
+    }
+
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values::new(self.iter())
+    }
+
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut::new(self.iter_mut())
+    }
+
+    /// Empties the map, any in progress move is dropped with it.
+    /// Keeps the buckets asked for with with_capacity or reserve.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        let main = BucketList::with_buckets(self.min_buckets);
+        let main = std::mem::replace(&mut self.main, main);
+        let grow = std::mem::replace(&mut self.grow, BucketList::new());
+        self.moving = false;
+        self.n_moved = 0;
+        Drain::new(main, grow)
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving
+    }
+
+    /// Walks every bucket to get the chain lengths, so O(buckets)
+    pub fn stats(&self) -> HMapStats {
+        let chains = self
+            .main
+            .buckets
+            .iter()
+            .chain(self.grow.buckets.iter())
+            .map(|b| b.len())
+            .filter(|n| *n > 0);
+        let (used, max_chain) = chains.fold((0, 0), |(u, m), n| (u + 1, m.max(n)));
+        HMapStats {
+            len: self.len(),
+            capacity: self.capacity(),
+            bucket_count: if self.moving {
+                self.grow.buckets.len()
+            } else {
+                self.main.buckets.len()
+            },
+            migration_progress: if self.moving {
+                Some(self.n_moved as f64 / self.main.buckets.len() as f64)
+            } else {
+                None
+            },
+            max_chain,
+            avg_chain: if used == 0 {
+                0.0
+            } else {
+                self.len() as f64 / used as f64
+            },
+            rehashes: self.rehashes,
+            pairs_moved: self.pairs_moved,
+        }
+    }
+
     pub fn move_bucket(&mut self) {
-        if self.n_moved == 0 {
-            self.grow.set_buckets(self.main.buckets.len() * 2); // 数据过多 直接增长两倍的体积，然后搬运数据过去
+        if !self.moving {
+            // 数据过多 直接增长两倍的体积，然后搬运数据过去
+            self.start_move(self.main.buckets.len() * self.config.grow_factor.max(2));
+            return;
+        }
+        self.move_next();
+    }
+
+    // a move to n buckets, bigger or smaller, moving the first bucket now
+    fn start_move(&mut self, n: usize) {
+        self.grow.set_buckets(n);
+        self.moving = true;
+        self.move_next();
+    }
+
+    fn finish_move(&mut self) {
+        while self.moving {
+            self.move_next();
         }
-        if let Some(b) = self.main.bucket(self.n_moved) {
-            for (k, v) in b {
-                self.grow.push(k, v);
+    }
+
+    // Moves the pairs of one bucket, or move_budget of them if that is less,
+    // leaving the rest of a part moved bucket for the next call.
+    fn move_next(&mut self) {
+        let mut budget = self.config.move_budget.max(1);
+        // empty buckets cost nothing to move, so carry on past a few of them,
+        // otherwise a shrink would need one call per (mostly empty) bucket
+        let mut skipped = 0;
+        while self.n_moved < self.main.buckets.len() {
+            if self.main.buckets[self.n_moved].is_empty() {
+                self.n_moved += 1;
+                skipped += 1;
+                if skipped == BSIZE * 2 {
+                    return;
+                }
+                continue;
+            }
+            while budget > 0 {
+                let (k, v) = match self.main.pop(self.n_moved) {
+                    Some(kv) => kv,
+                    None => break,
+                };
+                let h = self.hasher.hash_one(&k);
+                self.grow.push(h, k, v);
+                self.pairs_moved += 1;
+                budget -= 1;
+            }
+            if self.main.buckets[self.n_moved].is_empty() {
+                self.n_moved += 1;
             }
-            self.n_moved += 1;
             return;
         }
 
         // if all data out of main into grow, then grow is main
         std::mem::swap(&mut self.main, &mut self.grow);
+        // drop the old buckets rather than keep them around, after a shrink they are the big ones
+        self.grow = BucketList::new();
+        self.moving = false;
         self.n_moved = 0;
+        self.rehashes += 1;
     }
 }
 
@@ -208,4 +622,356 @@ mod tests {
             assert!(x.len() < 12, "{}", msg);
         }
     }
+
+    // fill until a move between main and grow has started but not finished
+    fn mid_move() -> HMap<i32, i32> {
+        let mut hm = HMap::new();
+        let mut x = 0;
+        while !hm.moving || hm.len() < 100 || hm.main.len == 0 || hm.grow.len == 0 {
+            hm.insert(x, x * 2);
+            x += 1;
+        }
+        hm
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut hm = mid_move();
+        let n = hm.len() as i32;
+        for x in (0..n).step_by(2) {
+            assert_eq!(hm.remove(&x), Some(x * 2));
+        }
+        assert_eq!(hm.remove(&0), None);
+        assert_eq!(hm.len(), (n / 2) as usize);
+        for x in 0..n {
+            assert_eq!(hm.contains_key(&x), x % 2 == 1);
+        }
+
+        // keep inserting so the move finishes with the holes in place
+        for x in n..n * 4 {
+            hm.insert(x, x * 2);
+        }
+        for x in 0..n * 4 {
+            let want = if x < n && x % 2 == 0 { None } else { Some(x * 2) };
+            assert_eq!(hm.get(&x).copied(), want);
+        }
+    }
+
+    #[test]
+    fn test_insert_returns_old() {
+        let mut hm = HMap::new();
+        assert_eq!(hm.insert("a", 1), None);
+        assert_eq!(hm.insert("a", 2), Some(1));
+        assert_eq!(hm.len(), 1);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut hm = mid_move();
+        let n = hm.len() as i32;
+
+        *hm.entry(3).or_insert(0) += 1;
+        assert_eq!(hm.get(&3), Some(&7));
+
+        hm.entry(n + 5).and_modify(|v| *v = 0).or_insert_with(|| 99);
+        assert_eq!(hm.get(&(n + 5)), Some(&99));
+        hm.entry(n + 5).and_modify(|v| *v = 0).or_insert(99);
+        assert_eq!(hm.get(&(n + 5)), Some(&0));
+
+        // vacant inserts keep driving the move along
+        for x in n + 10..n * 5 {
+            let v = hm.entry(x).or_default();
+            assert_eq!(*v, 0);
+            *v = x;
+        }
+        assert_eq!(hm.get(&(n * 3)), Some(&(n * 3)));
+
+        if let Entry::Occupied(e) = hm.entry(1) {
+            assert_eq!(e.remove(), 2);
+        } else {
+            panic!("1 should be in the map");
+        }
+        assert!(!hm.contains_key(&1));
+        assert_eq!(hm.len(), (n * 5 - 10) as usize);
+    }
+
+    #[test]
+    fn test_iter_mid_move() {
+        let mut hm = mid_move();
+        let n = hm.len();
+        let mut seen: Vec<i32> = hm.keys().copied().collect();
+        seen.sort();
+        assert_eq!(seen, (0..n as i32).collect::<Vec<_>>());
+        assert_eq!(hm.iter().len(), n);
+        assert!(hm.iter().all(|(k, v)| *v == k * 2));
+
+        for v in hm.values_mut() {
+            *v += 1;
+        }
+        for (k, v) in &mut hm {
+            *v -= k * 2;
+        }
+        assert!(hm.values().all(|v| *v == 1));
+
+        let mut owned: Vec<(i32, i32)> = hm.into_iter().collect();
+        owned.sort();
+        assert_eq!(owned.len(), n);
+        assert_eq!(owned[5], (5, 1));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut hm = mid_move();
+        let n = hm.len();
+        let mut got: Vec<i32> = hm.drain().map(|(k, _)| k).collect();
+        got.sort();
+        assert_eq!(got, (0..n as i32).collect::<Vec<_>>());
+        assert!(hm.is_empty());
+        assert_eq!(hm.get(&3), None);
+
+        hm.insert(3, 4);
+        assert_eq!(hm.get(&3), Some(&4));
+    }
+
+    #[test]
+    fn test_other_hashers() {
+        use std::collections::hash_map::{DefaultHasher, RandomState};
+        use std::hash::BuildHasherDefault;
+
+        let mut sip: HMap<i32, i32, RandomState> = HMap::with_hasher(RandomState::new());
+        let mut fixed: HMap<i32, i32, BuildHasherDefault<DefaultHasher>> = HMap::default();
+        for x in 0..1000 {
+            sip.insert(x, x + 1);
+            fixed.insert(x, x + 1);
+        }
+        for x in 0..1000 {
+            assert_eq!(sip.get(&x), Some(&(x + 1)));
+            assert_eq!(fixed.get(&x), Some(&(x + 1)));
+        }
+        assert_eq!(sip.remove(&7), Some(8));
+        assert_eq!(sip.len(), 999);
+    }
+
+    #[test]
+    fn test_fixed_seed_same_layout() {
+        let mut a = HMap::with_hasher(MHashBuilder::with_seed(9));
+        let mut b = HMap::with_hasher(MHashBuilder::with_seed(9));
+        for x in 0..500 {
+            a.insert(x, ());
+            b.insert(x, ());
+        }
+        assert!(a.keys().eq(b.keys()));
+    }
+
+    fn buckets<K, V, S>(hm: &HMap<K, V, S>) -> usize {
+        hm.main.buckets.len().max(hm.grow.buckets.len())
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut hm = HMap::with_capacity(1000);
+        let start = buckets(&hm);
+        assert!(hm.capacity() >= 1000);
+        for x in 0..1000 {
+            hm.insert(x, x);
+        }
+        // no move should have been needed, unless one bucket got unlucky
+        assert!(buckets(&hm) <= start * 2);
+        for x in 0..1000 {
+            hm.remove(&x);
+        }
+        // never shrinks below what was asked for
+        assert_eq!(hm.main.buckets.len(), start);
+    }
+
+    #[test]
+    fn test_clear_keeps_capacity() {
+        let mut hm = HMap::with_capacity(1000);
+        let start = buckets(&hm);
+        for x in 0..500 {
+            hm.insert(x, x);
+        }
+        hm.clear();
+        assert!(hm.is_empty());
+        assert_eq!(buckets(&hm), start);
+        assert!(hm.capacity() >= 1000);
+
+        hm.reserve(5000);
+        let reserved = hm.capacity();
+        hm.drain();
+        assert_eq!(hm.capacity(), reserved);
+    }
+
+    #[test]
+    fn test_capacity_mid_shrink() {
+        let mut hm = HMap::new();
+        for x in 0..10_000 {
+            hm.insert(x, x);
+        }
+        hm.finish_move();
+        let peak = hm.capacity();
+        let mut x = 0;
+        while !hm.is_moving() {
+            hm.remove(&x);
+            x += 1;
+        }
+        assert!(hm.grow.buckets.len() < hm.main.buckets.len());
+        assert!(hm.capacity() < peak);
+        assert_eq!(hm.capacity(), hm.grow.buckets.len());
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut hm = mid_move();
+        let n = hm.len();
+        hm.reserve(10_000);
+        assert!(!hm.is_moving());
+        assert!(hm.capacity() >= n + 10_000);
+        for x in 0..n as i32 {
+            assert_eq!(hm.get(&x), Some(&(x * 2)));
+        }
+    }
+
+    #[test]
+    fn test_shrink_after_removes() {
+        let mut hm = HMap::new();
+        for x in 0..100_000 {
+            hm.insert(x, x);
+        }
+        let peak = buckets(&hm);
+        for x in 100..100_000 {
+            assert_eq!(hm.remove(&x), Some(x));
+        }
+        // a shrink moves along with each insert and remove,
+        // so keep churning like a long running service would
+        for _ in 0..50 {
+            for x in 0..100 {
+                hm.remove(&x);
+                hm.insert(x, x + 1);
+            }
+        }
+        assert!(
+            buckets(&hm) * 100 < peak,
+            "{} of {} buckets",
+            buckets(&hm),
+            peak
+        );
+        assert_eq!(hm.len(), 100);
+        for x in 0..100 {
+            assert_eq!(hm.get(&x), Some(&(x + 1)));
+        }
+        assert_eq!(hm.get(&100), None);
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut hm = HMap::with_capacity(10_000);
+        hm.insert(1, 1);
+        hm.insert(2, 2);
+        hm.shrink_to_fit();
+        assert!(!hm.is_moving());
+        assert_eq!(hm.main.buckets.len(), hm.config().buckets_for(2));
+        assert_eq!(hm.get(&2), Some(&2));
+    }
+
+    #[test]
+    fn test_config() {
+        // grow buckets are not held to max_bucket_len while a move fills them,
+        // so with a random seed the length check below fails now and then
+        let mut hm = HMap::with_hasher(MHashBuilder::with_seed(55));
+        hm.set_config(HMapConfig {
+            max_bucket_len: 2,
+            max_load: 0.5,
+            min_load: 0.0,
+            grow_factor: 4,
+            ..HMapConfig::default()
+        });
+        for x in 0..1000 {
+            hm.insert(x, x);
+        }
+        for (n, x) in hm.main.buckets.iter().enumerate() {
+            assert!(x.len() <= 3, "main bucket too big {}:{}", n, x.len());
+        }
+        assert!(buckets(&hm) >= 2000);
+        let before = buckets(&hm);
+        for x in 0..1000 {
+            hm.remove(&x);
+        }
+        assert_eq!(buckets(&hm), before);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_load must be above 0")]
+    fn test_config_zero_load() {
+        HMap::<i32, i32>::new().set_config(HMapConfig {
+            max_load: 0.0,
+            ..HMapConfig::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity overflow")]
+    fn test_reserve_overflow() {
+        let mut hm = HMap::new();
+        hm.insert(1, 1);
+        hm.reserve(usize::MAX - 1);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut hm = HMap::new();
+        let st = hm.stats();
+        assert_eq!((st.len, st.max_chain, st.rehashes), (0, 0, 0));
+        assert_eq!(st.migration_progress, None);
+
+        let mut seen_moving = false;
+        for x in 0..10_000 {
+            hm.insert(x, x);
+            if x % 7 != 0 {
+                continue;
+            }
+            let st = hm.stats();
+            if let Some(p) = st.migration_progress {
+                seen_moving = true;
+                assert!(hm.is_moving());
+                assert!((0.0..=1.0).contains(&p));
+            }
+            assert!(st.max_chain <= hm.config().max_bucket_len + 1);
+        }
+        assert!(seen_moving);
+        let st = hm.stats();
+        assert_eq!(st.len, 10_000);
+        assert!(st.rehashes >= 10);
+        assert!(st.capacity >= 10_000 / 2);
+        assert!(st.avg_chain >= 1.0 && st.avg_chain <= st.max_chain as f64);
+        assert!(st.pairs_moved >= 10_000 / 2);
+    }
+
+    #[test]
+    fn test_move_budget() {
+        let mut hm = HMap::new();
+        hm.set_config(HMapConfig {
+            // long buckets, so a whole bucket would be well over budget
+            max_bucket_len: 64,
+            max_load: 16.0,
+            move_budget: 3,
+            ..HMapConfig::default()
+        });
+        // stats() walks every bucket, the counter it reads is enough here
+        let mut before = hm.pairs_moved;
+        for x in 0..20_000 {
+            hm.insert(x, x);
+            let after = hm.pairs_moved;
+            assert!(after - before <= 3, "insert {} moved {}", x, after - before);
+            before = after;
+        }
+        for x in 0..20_000 {
+            assert_eq!(hm.remove(&x), Some(x));
+            let after = hm.pairs_moved;
+            assert!(after - before <= 3, "remove {} moved {}", x, after - before);
+            before = after;
+        }
+        assert!(hm.stats().rehashes > 5);
+        assert!(hm.is_empty());
+    }
 }
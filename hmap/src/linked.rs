@@ -0,0 +1,472 @@
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+
+use crate::{Entry, HMap, MHashBuilder};
+
+const NIL: usize = usize::MAX;
+
+#[derive(Debug, Clone)]
+struct Node<K, V> {
+    k: K,
+    v: V,
+    prev: usize,
+    next: usize,
+}
+
+/// HMap that remembers order. The pairs live in a slab of nodes
+/// linked front to back by index, the HMap only maps each key to its node,
+/// so moving a pair in the order never touches the HMap.
+///
+/// The links are not kept in the HMap's own value slots: a grow moves pairs
+/// between buckets, so nothing in there stays put for a link to point at,
+/// and a link would have to be the neighbour's key, cloned and hashed again
+/// on every step. Slab indexes never move, so the list lives there instead.
+/// Keys are stored twice (map and node) hence the Clone.
+#[derive(Debug, Clone)]
+pub struct LinkedHMap<K, V, S = MHashBuilder> {
+    map: HMap<K, usize, S>,
+    nodes: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>, // empty slots in nodes, reused before nodes grows
+    head: usize,
+    tail: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LinkedHMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_hasher(MHashBuilder::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Default for LinkedHMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, S: BuildHasher> LinkedHMap<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        LinkedHMap {
+            map: HMap::with_hasher(hasher),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    fn node(&self, i: usize) -> &Node<K, V> {
+        self.nodes[i].as_ref().expect("linked to an empty node")
+    }
+
+    fn node_mut(&mut self, i: usize) -> &mut Node<K, V> {
+        self.nodes[i].as_mut().expect("linked to an empty node")
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = {
+            let n = self.node(i);
+            (n.prev, n.next)
+        };
+        match prev {
+            NIL => self.head = next,
+            p => self.node_mut(p).next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            n => self.node_mut(n).prev = prev,
+        }
+    }
+
+    fn link_back(&mut self, i: usize) {
+        let tail = self.tail;
+        {
+            let n = self.node_mut(i);
+            n.prev = tail;
+            n.next = NIL;
+        }
+        match tail {
+            NIL => self.head = i,
+            t => self.node_mut(t).next = i,
+        }
+        self.tail = i;
+    }
+
+    fn take_node(&mut self, i: usize) -> (K, V) {
+        self.unlink(i);
+        let n = self.nodes[i].take().expect("linked to an empty node");
+        self.free.push(i);
+        (n.k, n.v)
+    }
+
+    /// New keys go to the back, an existing key keeps its place
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.put(k, v, false)
+    }
+
+    // One lookup through the entry API whether the key is new or not.
+    // to_back also moves an existing key to the back, as a use does in LruCache.
+    fn put(&mut self, k: K, v: V, to_back: bool) -> Option<V> {
+        let i = match self.map.entry(k) {
+            Entry::Occupied(e) => {
+                let i = *e.get();
+                let old = std::mem::replace(&mut self.node_mut(i).v, v);
+                if to_back && i != self.tail {
+                    self.unlink(i);
+                    self.link_back(i);
+                }
+                return Some(old);
+            }
+            Entry::Vacant(e) => {
+                let node = Node {
+                    k: e.key().clone(),
+                    v,
+                    prev: NIL,
+                    next: NIL,
+                };
+                // only the map is borrowed by e, so the slab can be filled meanwhile
+                let i = match self.free.pop() {
+                    Some(i) => {
+                        self.nodes[i] = Some(node);
+                        i
+                    }
+                    None => {
+                        self.nodes.push(Some(node));
+                        self.nodes.len() - 1
+                    }
+                };
+                e.insert(i);
+                i
+            }
+        };
+        self.link_back(i);
+        None
+    }
+
+    pub fn get<Q>(&self, q: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = *self.map.get(q)?;
+        Some(&self.node(i).v)
+    }
+
+    pub fn get_mut<Q>(&mut self, q: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = *self.map.get(q)?;
+        Some(&mut self.node_mut(i).v)
+    }
+
+    pub fn contains_key<Q>(&self, q: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains_key(q)
+    }
+
+    pub fn remove<Q>(&mut self, q: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = self.map.remove(q)?;
+        Some(self.take_node(i).1)
+    }
+
+    /// false if the key is not in the map
+    pub fn move_to_back<Q>(&mut self, q: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = match self.map.get(q) {
+            Some(&i) => i,
+            None => return false,
+        };
+        if i != self.tail {
+            self.unlink(i);
+            self.link_back(i);
+        }
+        true
+    }
+
+    pub fn front(&self) -> Option<(&K, &V)> {
+        match self.head {
+            NIL => None,
+            i => {
+                let n = self.node(i);
+                Some((&n.k, &n.v))
+            }
+        }
+    }
+
+    pub fn back(&self) -> Option<(&K, &V)> {
+        match self.tail {
+            NIL => None,
+            i => {
+                let n = self.node(i);
+                Some((&n.k, &n.v))
+            }
+        }
+    }
+
+    /// Takes out the oldest pair (or least recently moved to the back)
+    pub fn pop_front(&mut self) -> Option<(K, V)> {
+        let i = match self.head {
+            NIL => return None,
+            i => i,
+        };
+        let (k, v) = self.take_node(i);
+        self.map.remove(&k);
+        Some((k, v))
+    }
+
+    pub fn pop_back(&mut self) -> Option<(K, V)> {
+        let i = match self.tail {
+            NIL => return None,
+            i => i,
+        };
+        let (k, v) = self.take_node(i);
+        self.map.remove(&k);
+        Some((k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.nodes.clear();
+        self.free.clear();
+        self.head = NIL;
+        self.tail = NIL;
+    }
+
+    /// Front to back
+    pub fn iter(&self) -> LinkedIter<'_, K, V> {
+        LinkedIter {
+            nodes: &self.nodes,
+            at: self.head,
+            left: self.len(),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+pub struct LinkedIter<'a, K, V> {
+    nodes: &'a [Option<Node<K, V>>],
+    at: usize,
+    left: usize,
+}
+
+impl<'a, K, V> Iterator for LinkedIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.nodes.get(self.at)?.as_ref()?;
+        self.at = n.next;
+        self.left -= 1;
+        Some((&n.k, &n.v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl<K, V> ExactSizeIterator for LinkedIter<'_, K, V> {}
+
+/// Holds at most `cap` pairs, once full each insert of a new key
+/// drops whichever pair was used longest ago.
+/// Both get and insert count as a use.
+#[derive(Debug, Clone)]
+pub struct LruCache<K, V, S = MHashBuilder> {
+    list: LinkedHMap<K, V, S>,
+    cap: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    /// Panics if cap is 0
+    pub fn new(cap: usize) -> Self {
+        Self::with_hasher(cap, MHashBuilder::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, S: BuildHasher> LruCache<K, V, S> {
+    pub fn with_hasher(cap: usize, hasher: S) -> Self {
+        assert!(cap > 0, "LruCache needs room for at least one item");
+        LruCache {
+            list: LinkedHMap::with_hasher(hasher),
+            cap,
+        }
+    }
+
+    /// Returns the pair pushed out to make room, if any
+    pub fn insert(&mut self, k: K, v: V) -> Option<(K, V)> {
+        if self.list.put(k, v, true).is_some() {
+            return None;
+        }
+        // the new pair is at the back, so it is never the one pushed out
+        if self.list.len() > self.cap {
+            return self.list.pop_front();
+        }
+        None
+    }
+
+    pub fn get<Q>(&mut self, q: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if !self.list.move_to_back(q) {
+            return None;
+        }
+        self.list.back().map(|(_, v)| v)
+    }
+
+    pub fn get_mut<Q>(&mut self, q: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if !self.list.move_to_back(q) {
+            return None;
+        }
+        self.list.get_mut(q)
+    }
+
+    /// Looks without counting it as a use
+    pub fn peek<Q>(&self, q: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.list.get(q)
+    }
+
+    pub fn contains_key<Q>(&self, q: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.list.contains_key(q)
+    }
+
+    pub fn remove<Q>(&mut self, q: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.list.remove(q)
+    }
+
+    /// Drops the least recently used pair
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        self.list.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// Least recently used first
+    pub fn iter(&self) -> LinkedIter<'_, K, V> {
+        self.list.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_order() {
+        let mut m = LinkedHMap::new();
+        for x in [5, 3, 9, 1, 7] {
+            m.insert(x, x * 10);
+        }
+        assert_eq!(m.keys().copied().collect::<Vec<_>>(), vec![5, 3, 9, 1, 7]);
+        // overwriting keeps the place
+        assert_eq!(m.insert(3, 0), Some(30));
+        assert_eq!(m.remove(&9), Some(90));
+        assert!(m.move_to_back(&5));
+        assert!(!m.move_to_back(&100));
+        assert_eq!(
+            m.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+            vec![(3, 0), (1, 10), (7, 70), (5, 50)]
+        );
+        assert_eq!(m.pop_front(), Some((3, 0)));
+        assert_eq!(m.pop_back(), Some((5, 50)));
+        assert_eq!(m.front(), Some((&1, &10)));
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.iter().len(), 2);
+    }
+
+    #[test]
+    fn test_reuses_slots() {
+        let mut m = LinkedHMap::new();
+        for x in 0..10_000 {
+            m.insert(x, x);
+            if x >= 10 {
+                assert_eq!(m.pop_front(), Some((x - 10, x - 10)));
+            }
+        }
+        assert_eq!(m.len(), 10);
+        assert!(m.nodes.len() <= 11);
+        assert_eq!(m.keys().copied().collect::<Vec<_>>(), (9990..10_000).collect::<Vec<_>>());
+        m.clear();
+        assert!(m.is_empty());
+        assert_eq!(m.front(), None);
+        m.insert(1, 1);
+        assert_eq!(m.back(), Some((&1, &1)));
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut c = LruCache::new(3);
+        assert_eq!(c.insert("a", 1), None);
+        assert_eq!(c.insert("b", 2), None);
+        assert_eq!(c.insert("c", 3), None);
+        // a is used, so b is now the oldest
+        assert_eq!(c.get(&"a"), Some(&1));
+        assert_eq!(c.insert("d", 4), Some(("b", 2)));
+        assert!(!c.contains_key(&"b"));
+        // peek does not count as a use
+        assert_eq!(c.peek(&"c"), Some(&3));
+        assert_eq!(c.insert("e", 5), Some(("c", 3)));
+        // updating an existing key never pushes anything out
+        assert_eq!(c.insert("a", 10), None);
+        assert_eq!(c.len(), 3);
+        *c.get_mut(&"d").unwrap() += 1;
+        assert_eq!(
+            c.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+            vec![("e", 5), ("a", 10), ("d", 5)]
+        );
+        assert_eq!(c.pop_lru(), Some(("e", 5)));
+        assert_eq!(c.remove(&"a"), Some(10));
+        assert_eq!(c.len(), 1);
+    }
+}
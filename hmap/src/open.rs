@@ -0,0 +1,951 @@
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash},
+};
+
+use crate::{HMapStats, MHashBuilder};
+
+// Swiss table style control bytes, one per slot.
+// A full slot stores the top 7 bits of its hash so most
+// key compares can be skipped without touching the slot.
+const EMPTY: u8 = 0xFF;
+const DELETED: u8 = 0x80;
+
+// Control bytes are checked 8 at a time by reading them as one u64,
+// plain bit tricks instead of SIMD.
+const GROUP: usize = 8;
+const LO: u64 = 0x0101_0101_0101_0101;
+const HI: u64 = 0x8080_8080_8080_8080;
+
+// one bit (the top bit of a byte) per matching slot in a group
+struct BitMask(u64);
+
+impl BitMask {
+    fn any(&self) -> bool {
+        self.0 != 0
+    }
+}
+
+impl Iterator for BitMask {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        if self.0 == 0 {
+            return None;
+        }
+        let i = self.0.trailing_zeros() as usize / 8;
+        self.0 &= self.0 - 1;
+        Some(i)
+    }
+}
+
+// can report a false match next to a real one, callers compare keys anyway
+fn match_byte(group: u64, b: u8) -> BitMask {
+    let x = group ^ (LO * b as u64);
+    BitMask(x.wrapping_sub(LO) & !x & HI)
+}
+
+// EMPTY is the only control byte with both of its top two bits set
+fn match_empty(group: u64) -> BitMask {
+    BitMask(group & (group << 1) & HI)
+}
+
+fn match_empty_or_deleted(group: u64) -> BitMask {
+    BitMask(group & HI)
+}
+
+fn h2(h: u64) -> u8 {
+    (h >> 57) as u8
+}
+
+/// Flat open addressing table, the counterpart of BucketList.
+/// Like BucketList it is handed hashes by the map that owns it.
+#[derive(Debug)]
+struct OpenTable<K, V> {
+    len: usize,  // live pairs
+    used: usize, // live pairs and tombstones, what probing has to walk over
+    ctrl: Vec<u8>,
+    slots: Vec<Option<(K, V)>>,
+}
+
+impl<K: Eq, V> OpenTable<K, V> {
+    // cap must be a power of two and at least one group
+    fn with_slots(cap: usize) -> Self {
+        OpenTable {
+            len: 0,
+            used: 0,
+            ctrl: vec![EMPTY; cap],
+            slots: (0..cap).map(|_| None).collect(),
+        }
+    }
+
+    fn new() -> Self {
+        Self::with_slots(GROUP)
+    }
+
+    fn capacity(&self) -> usize {
+        self.ctrl.len()
+    }
+
+    // 7/8 full, counting tombstones
+    fn too_full(&self) -> bool {
+        (self.used + 1) * 8 > self.capacity() * 7
+    }
+
+    fn group(&self, start: usize) -> u64 {
+        let mut b = [0u8; GROUP];
+        b.copy_from_slice(&self.ctrl[start..start + GROUP]);
+        u64::from_le_bytes(b)
+    }
+
+    // start slot of every group in probe order,
+    // triangular steps visit each group once when the count is a power of two
+    fn probe(&self, h: u64) -> impl Iterator<Item = usize> {
+        let groups = self.capacity() / GROUP;
+        let mask = groups - 1;
+        let mut g = (h as usize) & mask;
+        (0..groups).map(move |stride| {
+            g = (g + stride) & mask;
+            g * GROUP
+        })
+    }
+
+    fn find<KB>(&self, h: u64, k: &KB) -> Option<usize>
+    where
+        K: Borrow<KB>,
+        KB: Eq + ?Sized,
+    {
+        for start in self.probe(h) {
+            let group = self.group(start);
+            for i in match_byte(group, h2(h)) {
+                if let Some((ik, _)) = &self.slots[start + i] {
+                    if k == ik.borrow() {
+                        return Some(start + i);
+                    }
+                }
+            }
+            if match_empty(group).any() {
+                return None;
+            }
+        }
+        None
+    }
+
+    // how many groups a find for a key at pos walks, for stats
+    fn probe_len(&self, h: u64, pos: usize) -> usize {
+        let group = pos / GROUP * GROUP;
+        self.probe(h).position(|start| start == group).unwrap_or(0) + 1
+    }
+
+    // caller makes sure k is not already here and the table is not full
+    fn push(&mut self, h: u64, k: K, v: V) -> usize {
+        let pos = self
+            .probe(h)
+            .find_map(|start| {
+                let group = self.group(start);
+                match_empty_or_deleted(group).next().map(|i| start + i)
+            })
+            .expect("open table has no free slot");
+        if self.ctrl[pos] == EMPTY {
+            self.used += 1;
+        }
+        self.ctrl[pos] = h2(h);
+        self.slots[pos] = Some((k, v));
+        self.len += 1;
+        pos
+    }
+
+    // Leaves a tombstone so probe chains through this slot still work.
+    // A find stops at the first group with an empty slot, and push only
+    // goes past a group with no free slot at all, so no key sits beyond
+    // a group that has an empty one: there the slot can be empty again.
+    fn take(&mut self, pos: usize) -> (K, V) {
+        if match_empty(self.group(pos / GROUP * GROUP)).any() {
+            self.ctrl[pos] = EMPTY;
+            self.used -= 1;
+        } else {
+            self.ctrl[pos] = DELETED;
+        }
+        self.len -= 1;
+        self.slots[pos].take().expect("slot taken twice")
+    }
+
+    fn pair(&self, pos: usize) -> &(K, V) {
+        self.slots[pos].as_ref().expect("empty slot")
+    }
+
+    fn pair_mut(&mut self, pos: usize) -> &mut (K, V) {
+        self.slots[pos].as_mut().expect("empty slot")
+    }
+
+    fn get<KB>(&self, h: u64, k: &KB) -> Option<&V>
+    where
+        K: Borrow<KB>,
+        KB: Eq + ?Sized,
+    {
+        let pos = self.find(h, k)?;
+        Some(&self.pair(pos).1)
+    }
+
+    fn get_mut<KB>(&mut self, h: u64, k: &KB) -> Option<&mut V>
+    where
+        K: Borrow<KB>,
+        KB: Eq + ?Sized,
+    {
+        let pos = self.find(h, k)?;
+        Some(&mut self.pair_mut(pos).1)
+    }
+
+    fn remove<KB>(&mut self, h: u64, k: &KB) -> Option<(K, V)>
+    where
+        K: Borrow<KB>,
+        KB: Eq + ?Sized,
+    {
+        let pos = self.find(h, k)?;
+        Some(self.take(pos))
+    }
+
+    // everything in group n, the same job BucketList::bucket does
+    fn group_items(&mut self, n: usize) -> Option<Vec<(K, V)>> {
+        let start = n * GROUP;
+        if start >= self.capacity() {
+            return None;
+        }
+        let mut res = Vec::new();
+        for pos in start..start + GROUP {
+            if self.slots[pos].is_some() {
+                res.push(self.take(pos));
+            }
+        }
+        Some(res)
+    }
+}
+
+// fewest slots (a power of two, at least a group) that hold n under 7/8 full
+fn slots_for(n: usize) -> usize {
+    n.checked_mul(8)
+        .map(|n| n.div_ceil(7))
+        .and_then(usize::checked_next_power_of_two)
+        .expect("capacity overflow")
+        .max(GROUP)
+}
+
+/// HMap with the same main/grow incremental move,
+/// but storing pairs in one flat open addressing table
+/// instead of a Vec per bucket.
+///
+/// It is its own type rather than a storage choice on HMap because much
+/// of HMap leans on a bucket being one Vec: HMapConfig's max_bucket_len
+/// and move_budget, the iterators and the Entry types all point into
+/// BucketList, and LinkedHMap, HSet and ConcurrentHMap are built on
+/// that. A key here can sit in any group along its probe, so the same
+/// calls are written again over OpenTable instead: the map calls,
+/// capacity, reserve and shrink_to_fit, shrinking after removes,
+/// the entry API and stats. Load limits are fixed, it has no HMapConfig.
+#[derive(Debug)]
+pub struct OpenHMap<K, V, S = MHashBuilder> {
+    moving: bool,
+    n_moved: usize, // groups already moved out of main
+    main: OpenTable<K, V>,
+    grow: OpenTable<K, V>,
+    hasher: S,
+    min_slots: usize, // never shrink below what with_capacity or reserve asked for
+    rehashes: usize,
+    pairs_moved: usize,
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default> Default for OpenHMap<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K: Hash + Eq, V> OpenHMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_hasher(MHashBuilder::new())
+    }
+
+    /// Room for n pairs before the first grow
+    pub fn with_capacity(n: usize) -> Self {
+        Self::with_capacity_and_hasher(n, MHashBuilder::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> OpenHMap<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    pub fn with_capacity_and_hasher(n: usize, hasher: S) -> Self {
+        let min_slots = slots_for(n);
+        OpenHMap {
+            moving: false,
+            n_moved: 0,
+            main: OpenTable::with_slots(min_slots),
+            grow: OpenTable::new(),
+            hasher,
+            min_slots,
+            rehashes: 0,
+            pairs_moved: 0,
+        }
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    /// How many pairs fit before the next grow, 7/8 of the slots.
+    /// Mid move that is the table being moved into.
+    pub fn capacity(&self) -> usize {
+        let slots = if self.moving {
+            self.grow.capacity()
+        } else {
+            self.main.capacity()
+        };
+        slots / 8 * 7
+    }
+
+    /// Makes sure `additional` more pairs fit without a grow.
+    /// Unlike the automatic grow this moves everything straight away.
+    /// Panics with "capacity overflow" if that many slots can not be had.
+    pub fn reserve(&mut self, additional: usize) {
+        let n = self
+            .len()
+            .checked_add(additional)
+            .expect("capacity overflow");
+        let want = slots_for(n);
+        self.min_slots = self.min_slots.max(want);
+        self.finish_move();
+        if want > self.main.capacity() {
+            self.start_move(want);
+            self.finish_move();
+        }
+    }
+
+    /// Moves everything into as few slots as hold the current pairs,
+    /// and forgets any capacity asked for before
+    pub fn shrink_to_fit(&mut self) {
+        self.min_slots = GROUP;
+        self.finish_move();
+        let want = slots_for(self.len());
+        if want < self.main.capacity() {
+            self.start_move(want);
+            self.finish_move();
+        }
+    }
+
+    /// Returns the old value if the key was already in the map
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let h = self.hasher.hash_one(&k);
+        if let Some(iv) = self.main.get_mut(h, &k) {
+            return Some(std::mem::replace(iv, v));
+        }
+        if let Some(iv) = self.grow.get_mut(h, &k) {
+            return Some(std::mem::replace(iv, v));
+        }
+        self.insert_new(h, k, v);
+        None
+    }
+
+    // Caller guarantees k is not in the map yet.
+    // As in HMap the move step comes before the push,
+    // so the new pair stays put and a reference to it can be returned.
+    fn insert_new(&mut self, h: u64, k: K, v: V) -> &mut V {
+        if self.moving {
+            self.move_next();
+            // inserts can outrun a shrink, it has to be done before grow fills up
+            if self.moving && self.grow.too_full() {
+                self.finish_move();
+            }
+        }
+        if !self.moving && self.main.too_full() {
+            // mostly tombstones just needs a clean table the same size
+            let cap = self.main.capacity();
+            self.start_move(if self.main.len * 2 >= cap { cap * 2 } else { cap });
+        }
+        let table = if self.moving {
+            &mut self.grow
+        } else {
+            &mut self.main
+        };
+        let pos = table.push(h, k, v);
+        &mut table.pair_mut(pos).1
+    }
+
+    pub fn get<KR>(&self, kr: &KR) -> Option<&V>
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        let h = self.hasher.hash_one(kr);
+        self.main.get(h, kr).or_else(|| self.grow.get(h, kr))
+    }
+
+    pub fn get_mut<KR>(&mut self, kr: &KR) -> Option<&mut V>
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        let h = self.hasher.hash_one(kr);
+        self.main.get_mut(h, kr).or_else(|| self.grow.get_mut(h, kr))
+    }
+
+    pub fn contains_key<KR>(&self, kr: &KR) -> bool
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        self.get(kr).is_some()
+    }
+
+    pub fn remove<KR>(&mut self, kr: &KR) -> Option<V>
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        self.remove_entry(kr).map(|(_, v)| v)
+    }
+
+    pub fn remove_entry<KR>(&mut self, kr: &KR) -> Option<(K, V)>
+    where
+        K: Borrow<KR>,
+        KR: Hash + Eq + ?Sized,
+    {
+        let h = self.hasher.hash_one(kr);
+        let res = self.main.remove(h, kr).or_else(|| self.grow.remove(h, kr));
+        if res.is_some() {
+            self.after_remove();
+        }
+        res
+    }
+
+    // removes keep a move going just like inserts do,
+    // and start a shrink once under a quarter of the slots are used
+    fn after_remove(&mut self) {
+        if self.moving {
+            self.move_next();
+            return;
+        }
+        let cap = self.main.capacity();
+        if cap > self.min_slots && self.main.len * 4 < cap {
+            // leave room to grow again before the next move is needed
+            let want = slots_for(self.main.len * 2).max(self.min_slots);
+            if want < cap {
+                self.start_move(want);
+            }
+        }
+    }
+
+    pub fn entry(&mut self, k: K) -> OpenEntry<'_, K, V, S> {
+        let h = self.hasher.hash_one(&k);
+        if let Some(pos) = self.main.find(h, &k) {
+            return OpenEntry::Occupied(OpenOccupiedEntry {
+                map: self,
+                in_grow: false,
+                pos,
+            });
+        }
+        if let Some(pos) = self.grow.find(h, &k) {
+            return OpenEntry::Occupied(OpenOccupiedEntry {
+                map: self,
+                in_grow: true,
+                pos,
+            });
+        }
+        OpenEntry::Vacant(OpenVacantEntry {
+            map: self,
+            hash: h,
+            key: k,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.main.len + self.grow.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Empties the map, any in progress move is dropped with it.
+    /// Keeps the slots asked for with with_capacity or reserve.
+    pub fn clear(&mut self) {
+        self.main = OpenTable::with_slots(self.min_slots);
+        self.grow = OpenTable::new();
+        self.moving = false;
+        self.n_moved = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.main
+            .slots
+            .iter()
+            .chain(self.grow.slots.iter())
+            .flatten()
+            .map(|(k, v)| (k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving
+    }
+
+    /// The same numbers as HMap::stats, with a group of 8 slots for a
+    /// bucket: max_chain and avg_chain are how many groups a find for each
+    /// key walks. Hashes every key, so O(slots).
+    pub fn stats(&self) -> HMapStats {
+        let (walked, max_chain) = [&self.main, &self.grow]
+            .into_iter()
+            .flat_map(|t| {
+                t.slots.iter().enumerate().filter_map(move |(pos, s)| {
+                    let (k, _) = s.as_ref()?;
+                    Some(t.probe_len(self.hasher.hash_one(k), pos))
+                })
+            })
+            .fold((0, 0), |(w, m), n| (w + n, m.max(n)));
+        let filling = if self.moving { &self.grow } else { &self.main };
+        HMapStats {
+            len: self.len(),
+            capacity: self.capacity(),
+            bucket_count: filling.capacity() / GROUP,
+            migration_progress: if self.moving {
+                Some(self.n_moved as f64 / (self.main.capacity() / GROUP) as f64)
+            } else {
+                None
+            },
+            max_chain,
+            avg_chain: if self.is_empty() {
+                0.0
+            } else {
+                walked as f64 / self.len() as f64
+            },
+            rehashes: self.rehashes,
+            pairs_moved: self.pairs_moved,
+        }
+    }
+
+    /// Moves one group (8 slots) from main to grow,
+    /// starting a grow first if no move is going on
+    pub fn move_bucket(&mut self) {
+        if !self.moving {
+            self.start_move(self.main.capacity() * 2);
+            return;
+        }
+        self.move_next();
+    }
+
+    // a move to a table of cap slots, bigger, smaller or the same size
+    fn start_move(&mut self, cap: usize) {
+        self.grow = OpenTable::with_slots(cap);
+        self.moving = true;
+        self.move_next();
+    }
+
+    fn finish_move(&mut self) {
+        while self.moving {
+            self.move_next();
+        }
+    }
+
+    // Moves the pairs of one group. Empty groups cost next to nothing,
+    // so carry on past a few of them, otherwise a shrink would take a call
+    // per (mostly empty) group.
+    fn move_next(&mut self) {
+        let mut skipped = 0;
+        while let Some(items) = self.main.group_items(self.n_moved) {
+            self.n_moved += 1;
+            if items.is_empty() {
+                skipped += 1;
+                if skipped == GROUP * 2 {
+                    return;
+                }
+                continue;
+            }
+            for (k, v) in items {
+                let h = self.hasher.hash_one(&k);
+                self.grow.push(h, k, v);
+                self.pairs_moved += 1;
+            }
+            return;
+        }
+
+        // if all data out of main into grow, then grow is main
+        std::mem::swap(&mut self.main, &mut self.grow);
+        self.grow = OpenTable::new();
+        self.moving = false;
+        self.n_moved = 0;
+        self.rehashes += 1;
+        // removes made during the move left tombstones in what is now main,
+        // past 1/16 of the slots move them out into a clean table straight off
+        if (self.main.used - self.main.len) * 16 > self.main.capacity() {
+            self.start_move(self.main.capacity());
+        }
+    }
+}
+
+pub enum OpenEntry<'a, K, V, S = MHashBuilder> {
+    Occupied(OpenOccupiedEntry<'a, K, V, S>),
+    Vacant(OpenVacantEntry<'a, K, V, S>),
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> OpenEntry<'a, K, V, S> {
+    pub fn or_insert(self, v: V) -> &'a mut V {
+        match self {
+            OpenEntry::Occupied(e) => e.into_mut(),
+            OpenEntry::Vacant(e) => e.insert(v),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> &'a mut V {
+        match self {
+            OpenEntry::Occupied(e) => e.into_mut(),
+            OpenEntry::Vacant(e) => e.insert(f()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let OpenEntry::Occupied(e) = &mut self {
+            f(e.get_mut());
+        }
+        self
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            OpenEntry::Occupied(e) => e.key(),
+            OpenEntry::Vacant(e) => e.key(),
+        }
+    }
+}
+
+/// Points straight at the slot the pair sits in,
+/// `in_grow` says which of the two tables that is
+pub struct OpenOccupiedEntry<'a, K, V, S = MHashBuilder> {
+    map: &'a mut OpenHMap<K, V, S>,
+    in_grow: bool,
+    pos: usize,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> OpenOccupiedEntry<'a, K, V, S> {
+    fn table(&self) -> &OpenTable<K, V> {
+        if self.in_grow {
+            &self.map.grow
+        } else {
+            &self.map.main
+        }
+    }
+
+    fn table_mut(&mut self) -> &mut OpenTable<K, V> {
+        if self.in_grow {
+            &mut self.map.grow
+        } else {
+            &mut self.map.main
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.table().pair(self.pos).0
+    }
+
+    pub fn get(&self) -> &V {
+        &self.table().pair(self.pos).1
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        let pos = self.pos;
+        &mut self.table_mut().pair_mut(pos).1
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        let table = if self.in_grow {
+            &mut self.map.grow
+        } else {
+            &mut self.map.main
+        };
+        &mut table.pair_mut(self.pos).1
+    }
+
+    pub fn insert(&mut self, v: V) -> V {
+        std::mem::replace(self.get_mut(), v)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    pub fn remove_entry(mut self) -> (K, V) {
+        let pos = self.pos;
+        let res = self.table_mut().take(pos);
+        self.map.after_remove();
+        res
+    }
+}
+
+/// Keeps the hash worked out in `OpenHMap::entry` so insert does not redo it
+pub struct OpenVacantEntry<'a, K, V, S = MHashBuilder> {
+    map: &'a mut OpenHMap<K, V, S>,
+    hash: u64,
+    key: K,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> OpenVacantEntry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, v: V) -> &'a mut V {
+        self.map.insert_new(self.hash, self.key, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_group() {
+        let g = u64::from_le_bytes([EMPTY, 3, DELETED, 3, 0x7f, EMPTY, 0, 3]);
+        assert_eq!(match_byte(g, 3).collect::<Vec<_>>(), vec![1, 3, 7]);
+        assert_eq!(match_empty(g).collect::<Vec<_>>(), vec![0, 5]);
+        assert_eq!(match_empty_or_deleted(g).collect::<Vec<_>>(), vec![0, 2, 5]);
+    }
+
+    #[test]
+    fn test_lots_of_numbers() {
+        let mut hm = OpenHMap::new();
+        for x in 0..10000 {
+            hm.insert(x, x + 250);
+        }
+        assert_eq!(hm.len(), 10000);
+        for x in 0..10000 {
+            assert_eq!(hm.get(&x), Some(&(x + 250)));
+        }
+        assert_eq!(hm.insert(500, 1), Some(750));
+        assert_eq!(hm.iter().count(), 10000);
+    }
+
+    #[test]
+    fn test_remove_keeps_moving() {
+        let mut hm = OpenHMap::new();
+        let mut x = 0;
+        while !hm.is_moving() {
+            hm.insert(x, x);
+            x += 1;
+        }
+        let groups = hm.main.capacity() / GROUP;
+        assert!(x > groups);
+        // nothing but removes from here, they have to finish the move on their own
+        for y in 0..groups {
+            assert_eq!(hm.remove(&y), Some(y));
+        }
+        assert!(!hm.is_moving());
+        assert_eq!(hm.grow.len, 0);
+        assert_eq!(hm.main.capacity(), groups * GROUP * 2);
+        assert_eq!(hm.len(), x - groups);
+        for y in groups..x {
+            assert_eq!(hm.get(&y), Some(&y));
+        }
+    }
+
+    #[test]
+    fn test_remove_and_reuse() {
+        let mut hm = OpenHMap::new();
+        // churn through many more keys than are ever live at once,
+        // tombstones must get cleaned up by the same size moves
+        for x in 0..5000 {
+            hm.insert(x, x);
+            if x >= 20 {
+                assert_eq!(hm.remove(&(x - 20)), Some(x - 20));
+            }
+        }
+        assert_eq!(hm.len(), 20);
+        assert!(hm.main.capacity() <= 64, "cap {}", hm.main.capacity());
+        for x in 4980..5000 {
+            assert!(hm.contains_key(&x));
+        }
+        assert!(!hm.contains_key(&4979));
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut hm = OpenHMap::new();
+        let mut x = 0;
+        while !hm.is_moving() || hm.grow.len == 0 {
+            hm.insert(x, x * 2);
+            x += 1;
+        }
+        *hm.entry(3).or_insert(0) += 1;
+        assert_eq!(hm.get(&3), Some(&7));
+        hm.entry(x + 5).and_modify(|v| *v = 0).or_insert_with(|| 99);
+        assert_eq!(hm.get(&(x + 5)), Some(&99));
+        hm.entry(x + 5).and_modify(|v| *v = 0).or_insert(99);
+        assert_eq!(hm.get(&(x + 5)), Some(&0));
+        // vacant inserts keep driving the move along
+        for y in x + 10..x * 5 {
+            *hm.entry(y).or_default() = y;
+        }
+        assert_eq!(hm.get(&(x * 3)), Some(&(x * 3)));
+        match hm.entry(1) {
+            OpenEntry::Occupied(e) => assert_eq!(e.remove(), 2),
+            OpenEntry::Vacant(_) => panic!("1 should be in the map"),
+        }
+        assert!(!hm.contains_key(&1));
+        assert_eq!(hm.len(), x * 5 - 10);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut hm = OpenHMap::with_capacity(1000);
+        let start = hm.main.capacity();
+        assert!(hm.capacity() >= 1000);
+        for x in 0..1000 {
+            hm.insert(x, x);
+        }
+        assert_eq!(hm.stats().rehashes, 0);
+        for x in 0..1000 {
+            hm.remove(&x);
+        }
+        // never shrinks below what was asked for
+        assert_eq!(hm.main.capacity(), start);
+        hm.clear();
+        assert_eq!(hm.main.capacity(), start);
+
+        hm.insert(1, 1);
+        hm.reserve(10_000);
+        assert!(!hm.is_moving());
+        assert!(hm.capacity() >= 10_001);
+        hm.shrink_to_fit();
+        assert_eq!(hm.main.capacity(), GROUP);
+        assert_eq!(hm.get(&1), Some(&1));
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity overflow")]
+    fn test_reserve_overflow() {
+        let mut hm = OpenHMap::new();
+        hm.insert(1, 1);
+        hm.reserve(usize::MAX - 1);
+    }
+
+    #[test]
+    fn test_shrink_after_removes() {
+        let mut hm = OpenHMap::new();
+        for x in 0..100_000 {
+            hm.insert(x, x);
+        }
+        let peak = hm.main.capacity().max(hm.grow.capacity());
+        for x in 100..100_000 {
+            assert_eq!(hm.remove(&x), Some(x));
+        }
+        for _ in 0..50 {
+            for x in 0..100 {
+                hm.remove(&x);
+                hm.insert(x, x + 1);
+            }
+        }
+        let now = hm.main.capacity().max(hm.grow.capacity());
+        assert!(now * 100 < peak, "{} of {} slots", now, peak);
+        for x in 0..100 {
+            assert_eq!(hm.get(&x), Some(&(x + 1)));
+        }
+        assert_eq!(hm.len(), 100);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut hm = OpenHMap::new();
+        let st = hm.stats();
+        assert_eq!((st.len, st.max_chain, st.rehashes), (0, 0, 0));
+        let mut seen_moving = false;
+        for x in 0..10_000 {
+            hm.insert(x, x);
+            if x % 97 == 0 {
+                let st = hm.stats();
+                if let Some(p) = st.migration_progress {
+                    seen_moving = true;
+                    assert!((0.0..=1.0).contains(&p));
+                }
+            }
+        }
+        assert!(seen_moving);
+        let st = hm.stats();
+        assert_eq!(st.len, 10_000);
+        assert!(st.rehashes >= 10);
+        assert!(st.avg_chain >= 1.0 && st.avg_chain <= st.max_chain as f64);
+        assert!(st.pairs_moved >= 10_000 / 2);
+    }
+
+    // every key starts its probe at group 0, so groups fill up
+    // and removes from them have to leave tombstones
+    #[derive(Default)]
+    struct Lumpy(u64);
+
+    impl std::hash::Hasher for Lumpy {
+        fn write(&mut self, b: &[u8]) {
+            for x in b {
+                self.0 = self.0 << 8 | *x as u64;
+            }
+        }
+
+        fn finish(&self) -> u64 {
+            self.0 << 32
+        }
+    }
+
+    #[test]
+    fn test_tombstones_cleared_after_move() {
+        use std::hash::BuildHasherDefault;
+        let lumpy = BuildHasherDefault::<Lumpy>::default();
+        let mut hm = OpenHMap::with_capacity_and_hasher(100, lumpy);
+        for x in 0..100 {
+            hm.insert(x, x);
+        }
+        hm.start_move(hm.main.capacity());
+        while hm.main.len > 0 {
+            hm.move_next();
+        }
+        assert!(hm.is_moving());
+        // what removes mid move do to grow, its groups are full so they leave tombstones
+        for x in 0..50 {
+            let h = hm.hasher.hash_one(x);
+            assert_eq!(hm.grow.remove(h, &x), Some((x, x)));
+        }
+        assert!((hm.grow.used - hm.grow.len) * 16 > hm.grow.capacity());
+        hm.finish_move();
+        assert_eq!(hm.main.used, hm.main.len);
+        assert_eq!(hm.stats().rehashes, 2);
+        for x in 0..100 {
+            assert_eq!(hm.get(&x).is_some(), x >= 50);
+        }
+    }
+
+    #[test]
+    fn test_take_empties_when_it_can() {
+        let mut hm = OpenHMap::with_capacity(100);
+        for x in 0..10 {
+            hm.insert(x, x);
+        }
+        for x in 0..10 {
+            hm.remove(&x);
+        }
+        // the groups all had empty slots, so no tombstones were left
+        assert_eq!(hm.main.used, 0);
+    }
+}
@@ -0,0 +1,346 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use crate::{HMap, MHashBuilder};
+
+/// A set is a map where only the keys matter
+#[derive(Clone)]
+pub struct HSet<T, S = MHashBuilder> {
+    map: HMap<T, (), S>,
+}
+
+impl<T: Hash + Eq> HSet<T> {
+    pub fn new() -> Self {
+        HSet { map: HMap::new() }
+    }
+
+    pub fn with_capacity(n: usize) -> Self {
+        HSet {
+            map: HMap::with_capacity(n),
+        }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher + Default> Default for HSet<T, S> {
+    fn default() -> Self {
+        HSet {
+            map: HMap::default(),
+        }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher> HSet<T, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        HSet {
+            map: HMap::with_hasher(hasher),
+        }
+    }
+
+    /// false if it was already in the set
+    pub fn insert(&mut self, t: T) -> bool {
+        self.map.insert(t, ()).is_none()
+    }
+
+    /// false if it was not in the set
+    pub fn remove<Q>(&mut self, q: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.remove(q).is_some()
+    }
+
+    /// Removes and hands back the value stored in the set
+    pub fn take<Q>(&mut self, q: &Q) -> Option<T>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.remove_entry(q).map(|(t, _)| t)
+    }
+
+    pub fn contains<Q>(&self, q: &Q) -> bool
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains_key(q)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub fn iter(&self) -> SetIter<'_, T> {
+        SetIter {
+            inner: self.map.keys(),
+        }
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.map.drain().map(|(t, _)| t)
+    }
+
+    /// Everything in self that is not in other
+    pub fn difference<'a>(&'a self, other: &'a HSet<T, S>) -> Difference<'a, T, S> {
+        Difference {
+            iter: self.iter(),
+            other,
+        }
+    }
+
+    /// Everything in exactly one of the two
+    pub fn symmetric_difference<'a>(
+        &'a self,
+        other: &'a HSet<T, S>,
+    ) -> SymmetricDifference<'a, T, S> {
+        SymmetricDifference {
+            iter: self.difference(other).chain(other.difference(self)),
+        }
+    }
+
+    /// Everything in both, walks whichever set is smaller
+    pub fn intersection<'a>(&'a self, other: &'a HSet<T, S>) -> Intersection<'a, T, S> {
+        let (small, big) = if self.len() <= other.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Intersection {
+            iter: small.iter(),
+            other: big,
+        }
+    }
+
+    /// Everything in either, each item once
+    pub fn union<'a>(&'a self, other: &'a HSet<T, S>) -> Union<'a, T, S> {
+        Union {
+            iter: self.iter().chain(other.difference(self)),
+        }
+    }
+
+    pub fn is_disjoint(&self, other: &HSet<T, S>) -> bool {
+        self.intersection(other).next().is_none()
+    }
+
+    pub fn is_subset(&self, other: &HSet<T, S>) -> bool {
+        self.len() <= other.len() && self.iter().all(|t| other.contains(t))
+    }
+
+    pub fn is_superset(&self, other: &HSet<T, S>) -> bool {
+        other.is_subset(self)
+    }
+}
+
+pub struct SetIter<'a, T> {
+    inner: crate::Keys<'a, T, ()>,
+}
+
+impl<'a, T> Iterator for SetIter<'a, T> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<T> ExactSizeIterator for SetIter<'_, T> {}
+
+pub struct Difference<'a, T, S> {
+    iter: SetIter<'a, T>,
+    other: &'a HSet<T, S>,
+}
+
+impl<'a, T: Hash + Eq, S: BuildHasher> Iterator for Difference<'a, T, S> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        let other = self.other;
+        self.iter.find(|t| !other.contains(*t))
+    }
+}
+
+pub struct Intersection<'a, T, S> {
+    iter: SetIter<'a, T>,
+    other: &'a HSet<T, S>,
+}
+
+impl<'a, T: Hash + Eq, S: BuildHasher> Iterator for Intersection<'a, T, S> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        let other = self.other;
+        self.iter.find(|t| other.contains(*t))
+    }
+}
+
+pub struct SymmetricDifference<'a, T, S> {
+    iter: std::iter::Chain<Difference<'a, T, S>, Difference<'a, T, S>>,
+}
+
+impl<'a, T: Hash + Eq, S: BuildHasher> Iterator for SymmetricDifference<'a, T, S> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        self.iter.next()
+    }
+}
+
+pub struct Union<'a, T, S> {
+    iter: std::iter::Chain<SetIter<'a, T>, Difference<'a, T, S>>,
+}
+
+impl<'a, T: Hash + Eq, S: BuildHasher> Iterator for Union<'a, T, S> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        self.iter.next()
+    }
+}
+
+impl<T, S> IntoIterator for HSet<T, S> {
+    type Item = T;
+    type IntoIter = std::iter::Map<crate::IntoIter<T, ()>, fn((T, ())) -> T>;
+    fn into_iter(self) -> Self::IntoIter {
+        let key: fn((T, ())) -> T = |(t, _)| t;
+        self.map.into_iter().map(key)
+    }
+}
+
+impl<'a, T: Hash + Eq, S: BuildHasher> IntoIterator for &'a HSet<T, S> {
+    type Item = &'a T;
+    type IntoIter = SetIter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher> Extend<T> for HSet<T, S> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.map.extend(iter.into_iter().map(|t| (t, ())));
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher + Default> FromIterator<T> for HSet<T, S> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        HSet {
+            map: iter.into_iter().map(|t| (t, ())).collect(),
+        }
+    }
+}
+
+impl<T: fmt::Debug, S> fmt::Debug for HSet<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries((&self.map).into_iter().map(|(t, _)| t)).finish()
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher> PartialEq for HSet<T, S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.map == other.map
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher> Eq for HSet<T, S> {}
+
+impl<T: Serialize, S> Serialize for HSet<T, S> {
+    fn serialize<SE: Serializer>(&self, s: SE) -> Result<SE::Ok, SE::Error> {
+        s.collect_seq((&self.map).into_iter().map(|(t, _)| t))
+    }
+}
+
+impl<'de, T, S> Deserialize<'de> for HSet<T, S>
+where
+    T: Deserialize<'de> + Hash + Eq,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let v: Vec<T> = Vec::deserialize(d)?;
+        Ok(v.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(v: &[i32]) -> HSet<i32> {
+        v.iter().copied().collect()
+    }
+
+    fn sorted<'a, I: Iterator<Item = &'a i32>>(i: I) -> Vec<i32> {
+        let mut v: Vec<i32> = i.copied().collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn test_basics() {
+        let mut s = HSet::new();
+        assert!(s.insert("a"));
+        assert!(!s.insert("a"));
+        assert!(s.insert("b"));
+        assert!(s.contains(&"a"));
+        assert_eq!(s.len(), 2);
+        assert!(s.remove(&"a"));
+        assert!(!s.remove(&"a"));
+        assert_eq!(s.take(&"b"), Some("b"));
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn test_algebra() {
+        let a = set(&[1, 2, 3, 4]);
+        let b = set(&[3, 4, 5]);
+        assert_eq!(sorted(a.union(&b)), vec![1, 2, 3, 4, 5]);
+        assert_eq!(sorted(a.intersection(&b)), vec![3, 4]);
+        assert_eq!(sorted(b.intersection(&a)), vec![3, 4]);
+        assert_eq!(sorted(a.difference(&b)), vec![1, 2]);
+        assert_eq!(sorted(b.difference(&a)), vec![5]);
+        assert_eq!(sorted(a.symmetric_difference(&b)), vec![1, 2, 5]);
+
+        assert!(set(&[3, 4]).is_subset(&a));
+        assert!(!b.is_subset(&a));
+        assert!(a.is_superset(&set(&[1])));
+        assert!(set(&[]).is_subset(&a));
+        assert!(a.is_disjoint(&set(&[9, 10])));
+        assert!(!a.is_disjoint(&b));
+    }
+
+    #[test]
+    fn test_big_sets() {
+        // big enough that both have been through a few moves
+        let a: HSet<i32> = (0..1000).collect();
+        let b: HSet<i32> = (500..1500).collect();
+        assert_eq!(a.union(&b).count(), 1500);
+        assert_eq!(a.intersection(&b).count(), 500);
+        assert_eq!(a.symmetric_difference(&b).count(), 1000);
+        let mut c = a.clone();
+        c.extend(500..1500);
+        assert_eq!(c, a.union(&b).copied().collect());
+    }
+
+    #[test]
+    fn test_traits() {
+        let a = set(&[1]);
+        assert_eq!(format!("{:?}", a), "{1}");
+        let s = serde_json::to_string(&a).unwrap();
+        assert_eq!(s, "[1]");
+        let back: HSet<i32> = serde_json::from_str("[1, 2, 2]").unwrap();
+        assert_eq!(back, set(&[1, 2]));
+        let mut v: Vec<i32> = back.into_iter().collect();
+        v.sort();
+        assert_eq!(v, vec![1, 2]);
+    }
+}
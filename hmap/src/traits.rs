@@ -0,0 +1,219 @@
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::ops::Index;
+
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+use crate::HMap;
+
+// Everything here treats HMap as a plain map,
+// how far along a move between main and grow is never shows.
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for HMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self).finish()
+    }
+}
+
+impl<K, V, S> PartialEq for HMap<K, V, S>
+where
+    K: Hash + Eq,
+    V: PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: Hash + Eq, V: Eq, S: BuildHasher> Eq for HMap<K, V, S> {}
+
+impl<K, Q, V, S> Index<&Q> for HMap<K, V, S>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    S: BuildHasher,
+{
+    type Output = V;
+
+    /// Panics if the key is not in the map
+    fn index(&self, k: &Q) -> &V {
+        self.get(k).expect("key not in HMap")
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Extend<(K, V)> for HMap<K, V, S> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<'a, K, V, S> Extend<(&'a K, &'a V)> for HMap<K, V, S>
+where
+    K: Hash + Eq + Copy,
+    V: Copy,
+    S: BuildHasher,
+{
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(k, v)| (*k, *v)));
+    }
+}
+
+impl<K, V, S> FromIterator<(K, V)> for HMap<K, V, S>
+where
+    K: Hash + Eq,
+    S: BuildHasher + Default,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut res = HMap::default();
+        res.extend(iter);
+        res
+    }
+}
+
+impl<K: Serialize, V: Serialize, S> Serialize for HMap<K, V, S> {
+    fn serialize<SE: Serializer>(&self, s: SE) -> Result<SE::Ok, SE::Error> {
+        s.collect_map(self)
+    }
+}
+
+struct HMapVisitor<K, V, S> {
+    marker: PhantomData<HMap<K, V, S>>,
+}
+
+impl<'de, K, V, S> Visitor<'de> for HMapVisitor<K, V, S>
+where
+    K: Deserialize<'de> + Hash + Eq,
+    V: Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    type Value = HMap<K, V, S>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+        // the length comes from the input, so do not trust it with more than
+        // a few buckets, the map grows as the pairs really turn up
+        let n = access.size_hint().unwrap_or(0).min(4096);
+        let mut res = HMap::with_capacity_and_hasher(n, S::default());
+        while let Some((k, v)) = access.next_entry()? {
+            res.insert(k, v);
+        }
+        Ok(res)
+    }
+}
+
+impl<'de, K, V, S> Deserialize<'de> for HMap<K, V, S>
+where
+    K: Deserialize<'de> + Hash + Eq,
+    V: Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_map(HMapVisitor {
+            marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{HMap, MHashBuilder};
+
+    fn filled() -> HMap<String, i32> {
+        let mut hm = HMap::new();
+        for x in 0..300 {
+            hm.insert(format!("k{}", x), x);
+        }
+        hm
+    }
+
+    #[test]
+    fn test_debug_is_plain_map() {
+        let mut hm = HMap::new();
+        hm.insert("a", 1);
+        assert_eq!(format!("{:?}", hm), r#"{"a": 1}"#);
+    }
+
+    #[test]
+    fn test_eq_ignores_layout() {
+        let a = filled();
+        // same pairs, other seed and insert order, so nothing lines up inside
+        let mut b = HMap::with_hasher(MHashBuilder::with_seed(1));
+        for x in (0..300).rev() {
+            b.insert(format!("k{}", x), x);
+        }
+        assert_eq!(a.len(), b.len());
+        assert!(a.iter().all(|(k, v)| b.get(k) == Some(v)));
+        let b: HMap<String, i32> = b.into_iter().collect();
+        assert_eq!(a, b);
+
+        let mut c = a.clone();
+        assert_eq!(a, c);
+        c.insert("k0".to_string(), 7);
+        assert_ne!(a, c);
+        c.insert("k0".to_string(), 0);
+        c.insert("new".to_string(), 0);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_index_extend() {
+        let mut hm: HMap<&str, i32> = vec![("a", 1), ("b", 2)].into_iter().collect();
+        hm.extend(vec![("c", 3), ("a", 4)]);
+        hm.extend([("d", 5)].iter().map(|(k, v)| (k, v)));
+        assert_eq!(hm.len(), 4);
+        assert_eq!(hm["a"], 4);
+        assert_eq!(hm[&"d"], 5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_index_missing() {
+        let hm: HMap<i32, i32> = HMap::new();
+        let _ = hm[&3];
+    }
+
+    #[test]
+    fn test_json() {
+        let mut hm = HMap::new();
+        hm.insert("a".to_string(), 1);
+        assert_eq!(serde_json::to_string(&hm).unwrap(), r#"{"a":1}"#);
+
+        let hm = filled();
+        let s = serde_json::to_string(&hm).unwrap();
+        let back: HMap<String, i32> = serde_json::from_str(&s).unwrap();
+        assert_eq!(back, hm);
+
+        // and it reads what std's HashMap writes
+        let std_map: std::collections::HashMap<String, i32> = serde_json::from_str(&s).unwrap();
+        assert_eq!(std_map.len(), 300);
+        let back: HMap<String, i32> =
+            serde_json::from_str(&serde_json::to_string(&std_map).unwrap()).unwrap();
+        assert_eq!(back, hm);
+    }
+
+    #[test]
+    fn test_bincode() {
+        let hm = filled();
+        let b = bincode::serialize(&hm).unwrap();
+        let back: HMap<String, i32> = bincode::deserialize(&b).unwrap();
+        assert_eq!(back, hm);
+    }
+
+    #[test]
+    fn test_bincode_huge_len() {
+        // a map that says it has u64::MAX pairs, then ends
+        let b = u64::MAX.to_le_bytes();
+        assert!(bincode::deserialize::<HMap<String, i32>>(&b).is_err());
+        let b = (1u64 << 60).to_le_bytes();
+        assert!(bincode::deserialize::<HMap<u8, u8>>(&b).is_err());
+    }
+}